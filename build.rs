@@ -1,11 +1,34 @@
 #[cfg(windows)]
 extern crate winres;
 
-#[cfg(windows)]
+use std::{env, process::Command};
+
 fn main() {
-    let res = winres::WindowsResource::new();
-    let _ = res.compile();
-}
+    let revision = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_else(|| String::from("unknown"));
+    println!("cargo:rustc-env=BASECAMP_GIT_REVISION={}", revision);
 
-#[cfg(not(windows))]
-fn main() {}
+    let mut features = env::vars()
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase().replace('_', "-"))
+        })
+        .collect::<Vec<_>>();
+    features.sort();
+    println!("cargo:rustc-env=BASECAMP_FEATURES={}", features.join(","));
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+
+    #[cfg(windows)]
+    {
+        let res = winres::WindowsResource::new();
+        let _ = res.compile();
+    }
+}
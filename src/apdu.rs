@@ -0,0 +1,494 @@
+use crate::CONFIG;
+use data_encoding::{Encoding, HEXLOWER, HEXUPPER};
+use ring::digest;
+
+/// Destination address of the card terminal itself, receiving CT-BCS commands.
+const CT: u8 = 1;
+
+/// ISO 7816-4 command APDU.
+#[cfg_attr(test, derive(Debug, PartialEq))]
+pub struct CommandApdu<'a> {
+    pub cla: u8,
+    pub ins: u8,
+    pub p1: u8,
+    pub p2: u8,
+    pub data: &'a [u8],
+    /// Expected response length as decoded Ne, 256 or 65536 when Le is 0.
+    pub le: Option<usize>,
+}
+
+impl<'a> CommandApdu<'a> {
+    /// Parses short and extended length commands of cases 1 to 4.
+    pub fn parse(apdu: &'a [u8]) -> Option<Self> {
+        if apdu.len() < 4 {
+            return None;
+        }
+
+        let short = |le: u8| if le == 0 { 256 } else { le as usize };
+        let extended = |le: &[u8]| match u16::from_be_bytes([le[0], le[1]]) {
+            0 => 65536,
+            le => le as usize,
+        };
+
+        let body = &apdu[4..];
+        let (data, le) = match body {
+            [] => (&body[..0], None),
+            [le] => (&body[..0], Some(short(*le))),
+            [0, rest @ ..] if rest.len() == 2 => (&body[..0], Some(extended(rest))),
+            [0, high, low, rest @ ..] => {
+                let lc = u16::from_be_bytes([*high, *low]) as usize;
+                match rest.len().checked_sub(lc) {
+                    Some(0) if lc > 0 => (rest, None),
+                    Some(2) if lc > 0 => (&rest[..lc], Some(extended(&rest[lc..]))),
+                    _ => return None,
+                }
+            }
+            [0, ..] => return None,
+            [lc, rest @ ..] => {
+                let lc = *lc as usize;
+                match rest.len().checked_sub(lc) {
+                    Some(0) => (rest, None),
+                    Some(1) => (&rest[..lc], Some(short(rest[lc]))),
+                    _ => return None,
+                }
+            }
+        };
+
+        Some(CommandApdu {
+            cla: apdu[0],
+            ins: apdu[1],
+            p1: apdu[2],
+            p2: apdu[3],
+            data,
+            le,
+        })
+    }
+
+    /// Name of the command, as CT-BCS command if sent to the card terminal.
+    pub fn name(&self, dad: u8) -> Option<&'static str> {
+        if dad == CT && self.cla == 0x20 {
+            return match self.ins {
+                0x11 => Some("RESET CT"),
+                0x12 => Some("REQUEST ICC"),
+                0x13 => Some("GET STATUS"),
+                0x15 => Some("EJECT ICC"),
+                0x16 => Some("INPUT"),
+                0x17 => Some("OUTPUT"),
+                0x18 => Some("PERFORM VERIFICATION"),
+                0x19 => Some("MODIFY VERIFICATION DATA"),
+                _ => None,
+            };
+        }
+
+        match self.ins {
+            0x04 => Some("DEACTIVATE"),
+            0x0E => Some("ERASE BINARY"),
+            0x20 => Some("VERIFY"),
+            0x22 => Some("MANAGE SECURITY ENVIRONMENT"),
+            0x24 => Some("CHANGE REFERENCE DATA"),
+            0x2A => Some("PERFORM SECURITY OPERATION"),
+            0x2C => Some("RESET RETRY COUNTER"),
+            0x44 => Some("ACTIVATE"),
+            0x46 => Some("GENERATE ASYMMETRIC KEY PAIR"),
+            0x70 => Some("MANAGE CHANNEL"),
+            0x82 => Some("EXTERNAL AUTHENTICATE"),
+            0x84 => Some("GET CHALLENGE"),
+            0x86 | 0x87 => Some("GENERAL AUTHENTICATE"),
+            0x88 => Some("INTERNAL AUTHENTICATE"),
+            0xA2 => Some("SEARCH RECORD"),
+            0xA4 => Some("SELECT"),
+            0xB0 | 0xB1 => Some("READ BINARY"),
+            0xB2 | 0xB3 => Some("READ RECORD"),
+            0xC0 => Some("GET RESPONSE"),
+            0xCA | 0xCB => Some("GET DATA"),
+            0xD6 | 0xD7 => Some("UPDATE BINARY"),
+            0xDA | 0xDB => Some("PUT DATA"),
+            0xDC | 0xDD => Some("UPDATE RECORD"),
+            0xE2 => Some("APPEND RECORD"),
+            0xE4 => Some("DELETE"),
+            _ => None,
+        }
+    }
+}
+
+/// ISO 7816-4 response APDU.
+#[cfg_attr(test, derive(Debug, PartialEq))]
+pub struct ResponseApdu<'a> {
+    pub data: &'a [u8],
+    pub sw: u16,
+}
+
+impl<'a> ResponseApdu<'a> {
+    pub fn parse(apdu: &'a [u8]) -> Option<Self> {
+        if apdu.len() < 2 {
+            return None;
+        }
+
+        let (data, sw) = apdu.split_at(apdu.len() - 2);
+        Some(ResponseApdu {
+            data,
+            sw: u16::from_be_bytes([sw[0], sw[1]]),
+        })
+    }
+
+    /// Meaning of the status word, following CT-BCS for responses of the card terminal.
+    pub fn meaning(&self, dad: u8) -> String {
+        let [sw1, sw2] = self.sw.to_be_bytes();
+        let meaning = match (sw1, sw2) {
+            (0x90, 0x00) => "OK",
+            (0x90, 0x01) if dad == CT => "OK, asynchronous card",
+            (0x62, 0x00) if dad == CT => "no card present",
+            (0x62, 0x01) if dad == CT => "card already present and activated",
+            (0x64, 0x00) if dad == CT => "reset not successful",
+            (0x64, 0x01) if dad == CT => "process aborted by user",
+            (0x61, remaining) => return format!("{} more bytes available", remaining),
+            (0x62, 0x00) => "no information given",
+            (0x62, 0x81) => "part of returned data may be corrupted",
+            (0x62, 0x82) => "end of file reached before reading Le bytes",
+            (0x62, 0x83) => "selected file deactivated",
+            (0x63, retries) if retries & 0xF0 == 0xC0 => {
+                return format!("verification failed, {} retries left", retries & 0x0F)
+            }
+            (0x64, 0x00) => "execution error",
+            (0x65, 0x81) => "memory failure",
+            (0x67, 0x00) => "wrong length",
+            (0x68, 0x81) => "logical channel not supported",
+            (0x68, 0x82) => "secure messaging not supported",
+            (0x69, 0x82) => "security status not satisfied",
+            (0x69, 0x83) => "authentication method blocked",
+            (0x69, 0x84) => "reference data not usable",
+            (0x69, 0x85) => "conditions of use not satisfied",
+            (0x69, 0x86) => "command not allowed",
+            (0x69, 0x88) => "incorrect secure messaging data objects",
+            (0x6A, 0x80) => "incorrect data",
+            (0x6A, 0x81) => "function not supported",
+            (0x6A, 0x82) => "file not found",
+            (0x6A, 0x83) => "record not found",
+            (0x6A, 0x84) => "not enough memory",
+            (0x6A, 0x86) => "incorrect P1 P2",
+            (0x6A, 0x88) => "reference data not found",
+            (0x6A, 0x00) | (0x6B, 0x00) => "wrong parameters",
+            (0x6C, length) => return format!("wrong Le, {} bytes available", length),
+            (0x6D, 0x00) => "instruction not supported",
+            (0x6E, 0x00) => "class not supported",
+            (0x6F, 0x00) => "no precise diagnosis",
+            _ => "unknown",
+        };
+
+        meaning.to_string()
+    }
+}
+
+/// Decoded line for the debug log like `SELECT AID D27600000102 -> 9000 (OK)`.
+pub fn trace(dad: u8, command: &[u8], response: &[u8]) -> String {
+    let command = match CommandApdu::parse(command) {
+        Some(apdu) => describe(dad, &apdu),
+        None => format!("malformed command with {} bytes", command.len()),
+    };
+
+    let response = match ResponseApdu::parse(response) {
+        Some(apdu) if apdu.data.is_empty() => {
+            format!("{:04X} ({})", apdu.sw, apdu.meaning(dad))
+        }
+        Some(apdu) => format!(
+            "{} bytes, {:04X} ({})",
+            apdu.data.len(),
+            apdu.sw,
+            apdu.meaning(dad)
+        ),
+        None => String::from("no response"),
+    };
+
+    format!("{} -> {}", command, response)
+}
+
+fn describe(dad: u8, apdu: &CommandApdu<'_>) -> String {
+    let policy = if carries_pin(&[apdu.cla, apdu.ins]) {
+        None
+    } else {
+        Some(CONFIG.read().log_apdu)
+    };
+
+    let mut line = match apdu.name(dad) {
+        Some(name) => name.to_string(),
+        None => format!("CLA {:02X} INS {:02X}", apdu.cla, apdu.ins),
+    };
+
+    match (apdu.name(dad), apdu.p1) {
+        (Some("SELECT"), 0x04) => {
+            line.push_str(&format!(" AID {}", redact(apdu.data, policy, &HEXUPPER)))
+        }
+        (Some("SELECT"), 0x00..=0x02) if !apdu.data.is_empty() => {
+            line.push_str(&format!(" FID {}", redact(apdu.data, policy, &HEXUPPER)))
+        }
+        _ => {
+            line.push_str(&format!(" P1 {:02X} P2 {:02X}", apdu.p1, apdu.p2));
+            if !apdu.data.is_empty() {
+                line.push_str(&format!(" data {}", redact(apdu.data, policy, &HEXUPPER)));
+            }
+        }
+    }
+
+    if let Some(le) = apdu.le {
+        line.push_str(&format!(" Le {}", le));
+    }
+
+    line
+}
+
+/// How APDU payloads are written to the debug log. Data of PIN commands is always masked.
+#[derive(Clone, Copy, Deserialize)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[serde(rename_all = "kebab-case")]
+pub enum Policy {
+    /// Complete payload in hex.
+    Full,
+    /// Length of the payload only.
+    HeaderOnly,
+    /// Length and a SHA-256 prefix of the payload, to compare APDUs without revealing them.
+    Hashed,
+}
+
+/// Command APDU as hex for the debug log, with its data field redacted.
+pub fn command(apdu: &[u8]) -> String {
+    if apdu.len() <= 4 {
+        return HEXLOWER.encode(apdu);
+    }
+
+    let (header, body) = apdu.split_at(4);
+    let policy = if carries_pin(header) {
+        None
+    } else {
+        Some(CONFIG.read().log_apdu)
+    };
+
+    format!(
+        "{}{}",
+        HEXLOWER.encode(header),
+        redact(body, policy, &HEXLOWER)
+    )
+}
+
+/// Response APDU as hex for the debug log, with its data redacted but the status word kept.
+pub fn response(apdu: &[u8]) -> String {
+    if apdu.len() <= 2 {
+        return HEXLOWER.encode(apdu);
+    }
+
+    let (data, sw) = apdu.split_at(apdu.len() - 2);
+    format!(
+        "{}{}",
+        redact(data, Some(CONFIG.read().log_apdu), &HEXLOWER),
+        HEXLOWER.encode(sw)
+    )
+}
+
+/// Whether the command transports a PIN, in clear or as part of a CT-BCS verification.
+pub fn carries_pin(header: &[u8]) -> bool {
+    match (header[0], header[1]) {
+        // PERFORM VERIFICATION, MODIFY VERIFICATION DATA
+        (0x20, 0x18) | (0x20, 0x19) => true,
+        // VERIFY, CHANGE REFERENCE DATA, RESET RETRY COUNTER
+        (_, 0x20) | (_, 0x24) | (_, 0x2C) => true,
+        _ => false,
+    }
+}
+
+/// Redacts the bytes according to the policy, masking them completely without one.
+fn redact(bytes: &[u8], policy: Option<Policy>, hex: &Encoding) -> String {
+    match policy {
+        Some(Policy::Full) => hex.encode(bytes),
+        Some(Policy::HeaderOnly) => format!("[{} bytes]", bytes.len()),
+        Some(Policy::Hashed) => {
+            let hash = digest::digest(&digest::SHA256, bytes);
+            format!(
+                "[{} bytes sha256:{}]",
+                bytes.len(),
+                HEXLOWER.encode(&hash.as_ref()[..8])
+            )
+        }
+        None => format!("[{} bytes masked]", bytes.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{command, response, trace, CommandApdu, ResponseApdu};
+    use data_encoding::HEXUPPER;
+    use std::env::{remove_var, set_var};
+
+    fn hex(apdu: &str) -> Vec<u8> {
+        HEXUPPER.decode(apdu.as_bytes()).unwrap()
+    }
+
+    #[test]
+    #[serial]
+    fn pin_commands_are_masked() {
+        for policy in &["full", "header-only", "hashed"] {
+            set_var("K2_LOG_APDU", policy);
+            crate::tests::init_config_clear_map();
+
+            // VERIFY with format-2 PIN block
+            assert_eq!(
+                "00200081[9 bytes masked]",
+                command(&hex("002000810825123456FFFFFFFF"))
+            );
+            // CHANGE REFERENCE DATA
+            assert_eq!(
+                "00240001[9 bytes masked]",
+                command(&hex("00240001083132333435363738"))
+            );
+            // RESET RETRY COUNTER
+            assert_eq!(
+                "002c0001[9 bytes masked]",
+                command(&hex("002C0001083132333435363738"))
+            );
+            // PERFORM VERIFICATION, MODIFY VERIFICATION DATA
+            assert_eq!(
+                "20180100[11 bytes masked]",
+                command(&hex("201801000A52080020008108250000"))
+            );
+            assert_eq!(
+                "20190100[11 bytes masked]",
+                command(&hex("201901000A52080024000108250000"))
+            );
+        }
+
+        remove_var("K2_LOG_APDU");
+    }
+
+    #[test]
+    #[serial]
+    fn payload_policies() {
+        crate::tests::init_config_clear_map();
+        assert_eq!("00b08200[1 bytes]", command(&hex("00B0820000")));
+        assert_eq!("[2 bytes]9000", response(&hex("5A0A9000")));
+        assert_eq!("6a82", response(&hex("6A82")));
+
+        set_var("K2_LOG_APDU", "full");
+        crate::tests::init_config_clear_map();
+        assert_eq!("00b0820000", command(&hex("00B0820000")));
+        assert_eq!("5a0a9000", response(&hex("5A0A9000")));
+
+        set_var("K2_LOG_APDU", "hashed");
+        crate::tests::init_config_clear_map();
+        assert_eq!(
+            "[3 bytes sha256:ba7816bf8f01cfea]9000",
+            response(&hex("6162639000"))
+        );
+        assert_eq!("20110100", command(&hex("20110100")));
+
+        remove_var("K2_LOG_APDU");
+    }
+
+    #[test]
+    fn parse_command_cases() {
+        let apdu = |cla, ins, p1, p2, data, le| CommandApdu {
+            cla,
+            ins,
+            p1,
+            p2,
+            data,
+            le,
+        };
+
+        assert_eq!(
+            Some(apdu(0x20, 0x11, 0x01, 0x00, &[][..], None)),
+            CommandApdu::parse(&hex("20110100"))
+        );
+        assert_eq!(
+            Some(apdu(0x00, 0xB0, 0x82, 0x00, &[][..], Some(256))),
+            CommandApdu::parse(&hex("00B0820000"))
+        );
+        assert_eq!(
+            Some(apdu(0x00, 0xA4, 0x02, 0x0C, &[0x2F, 0x00][..], None)),
+            CommandApdu::parse(&hex("00A4020C022F00"))
+        );
+        assert_eq!(
+            Some(apdu(0x00, 0xA4, 0x04, 0x04, &[0xD2, 0x76][..], Some(256))),
+            CommandApdu::parse(&hex("00A4040402D27600"))
+        );
+        assert_eq!(
+            Some(apdu(0x00, 0xB0, 0x00, 0x00, &[][..], Some(65536))),
+            CommandApdu::parse(&hex("00B00000000000"))
+        );
+        assert_eq!(
+            Some(apdu(0x00, 0xD6, 0x00, 0x00, &[0x01, 0x02][..], None)),
+            CommandApdu::parse(&hex("00D600000000020102"))
+        );
+        assert_eq!(
+            Some(apdu(0x00, 0x2A, 0x9E, 0x9A, &[0xAB][..], Some(512))),
+            CommandApdu::parse(&hex("002A9E9A000001AB0200"))
+        );
+
+        assert_eq!(None, CommandApdu::parse(&hex("00A4")));
+        assert_eq!(None, CommandApdu::parse(&hex("00A4020C032F00")));
+        assert_eq!(None, CommandApdu::parse(&hex("00A4020C0000022F")));
+    }
+
+    #[test]
+    fn parse_response() {
+        assert_eq!(
+            Some(ResponseApdu {
+                data: &[0x5A, 0x0A][..],
+                sw: 0x9000
+            }),
+            ResponseApdu::parse(&hex("5A0A9000"))
+        );
+        assert_eq!(None, ResponseApdu::parse(&hex("90")));
+    }
+
+    #[test]
+    fn status_word_meanings() {
+        let meaning = |dad, sw: &str| ResponseApdu::parse(&hex(sw)).unwrap().meaning(dad);
+
+        assert_eq!("OK", meaning(0, "9000"));
+        assert_eq!("no card present", meaning(1, "6200"));
+        assert_eq!("no information given", meaning(0, "6200"));
+        assert_eq!("unknown", meaning(0, "9F10"));
+        assert_eq!("OK, asynchronous card", meaning(1, "9001"));
+        assert_eq!("verification failed, 2 retries left", meaning(0, "63C2"));
+        assert_eq!("16 more bytes available", meaning(0, "6110"));
+        assert_eq!("file not found", meaning(0, "6A82"));
+    }
+
+    #[test]
+    #[serial]
+    fn decoded_trace() {
+        set_var("K2_LOG_APDU", "full");
+        crate::tests::init_config_clear_map();
+
+        assert_eq!(
+            "SELECT AID D27600000102 -> 9000 (OK)",
+            trace(0, &hex("00A4040C06D27600000102"), &hex("9000"))
+        );
+        assert_eq!(
+            "READ BINARY P1 82 P2 00 Le 256 -> 12 bytes, 9000 (OK)",
+            trace(0, &hex("00B0820000"), &hex("5A0A802768831100001234569000"))
+        );
+        assert_eq!(
+            "VERIFY P1 00 P2 81 data [8 bytes masked] -> 63C2 (verification failed, 2 retries left)",
+            trace(0, &hex("002000810825123456FFFFFFFF"), &hex("63C2"))
+        );
+        assert_eq!(
+            "REQUEST ICC P1 01 P2 00 Le 256 -> 6200 (no card present)",
+            trace(1, &hex("2012010000"), &hex("6200"))
+        );
+        assert_eq!(
+            "CLA 80 INS FF P1 00 P2 00 -> no response",
+            trace(0, &hex("80FF0000"), &[])
+        );
+        assert_eq!(
+            "malformed command with 2 bytes -> 6700 (wrong length)",
+            trace(0, &hex("00A4"), &hex("6700"))
+        );
+
+        remove_var("K2_LOG_APDU");
+        crate::tests::init_config_clear_map();
+        assert_eq!(
+            "SELECT AID [6 bytes] -> 9000 (OK)",
+            trace(0, &hex("00A4040C06D27600000102"), &hex("9000"))
+        );
+    }
+}
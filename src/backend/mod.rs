@@ -0,0 +1,80 @@
+pub mod record;
+pub mod replay;
+pub mod rest;
+pub mod sim;
+
+use crate::ctapi::{status::Status, Terminal};
+use crate::CONFIG;
+use std::time::Instant;
+
+/// Backends CT-API calls can be answered by.
+#[derive(Clone, Copy, Deserialize)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// REST API of K2 peak.
+    Rest,
+    /// Simulated card terminals, see `sim_card_image`.
+    Sim,
+    /// Recorded session, see `replay_path`.
+    Replay,
+}
+
+/// Command APDU sent to a card terminal.
+pub struct Command<'a> {
+    pub dad: u8,
+    pub sad: u8,
+    pub apdu: &'a [u8],
+    /// Size of the buffer available for the response.
+    pub lenr: u16,
+}
+
+/// Response APDU of a card terminal.
+pub struct Response {
+    pub dad: u8,
+    pub sad: u8,
+    pub apdu: Vec<u8>,
+    pub status: Status,
+}
+
+pub trait Backend: Sync {
+    /// Opens a card terminal, returns the session if successful.
+    fn open(&self, ctn: u16, pn: u16) -> anyhow::Result<(Status, Option<Terminal>)>;
+
+    fn transmit(
+        &self,
+        ctn: u16,
+        terminal: &Terminal,
+        command: Command<'_>,
+    ) -> anyhow::Result<Response>;
+
+    fn close(&self, ctn: u16, terminal: &Terminal) -> anyhow::Result<Status>;
+
+    /// Closes a card terminal on shutdown, giving up at the deadline.
+    fn close_until(
+        &self,
+        ctn: u16,
+        terminal: &Terminal,
+        _deadline: Instant,
+    ) -> anyhow::Result<Status> {
+        self.close(ctn, terminal)
+    }
+}
+
+/// Backend handling the CT-API calls, recording them if configured.
+pub fn current() -> &'static dyn Backend {
+    if CONFIG.read().record_path.is_some() {
+        &record::Recorder
+    } else {
+        selected()
+    }
+}
+
+/// Backend selected in the configuration.
+fn selected() -> &'static dyn Backend {
+    match CONFIG.read().backend {
+        Kind::Rest => &rest::Rest,
+        Kind::Sim => &sim::Sim,
+        Kind::Replay => &replay::Replay,
+    }
+}
@@ -0,0 +1,327 @@
+use super::{Backend, Command, Response};
+use crate::ctapi::{status::Status, Terminal};
+use crate::{apdu, http, CONFIG};
+use data_encoding::{HEXLOWER, HEXUPPER};
+use ring::digest;
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Call {
+    Init,
+    Data,
+    Close,
+}
+
+/// Single CT-API call of a recorded session, stored as one line of JSON.
+#[derive(Deserialize, Serialize)]
+pub struct Exchange {
+    pub time: String,
+    pub call: Call,
+    pub ctn: u16,
+    pub pn: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dad: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sad: Option<u8>,
+    /// Command APDU, hex encoded. Only the header of a command carrying a PIN.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    /// SHA-256 of a command carrying a PIN, to match it on replay. As PINs are short, it does
+    /// not keep them secret, so recordings are still to be protected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command_sha256: Option<String>,
+    /// Response APDU, hex encoded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_dad: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_sad: Option<u8>,
+    pub status: i8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+impl Exchange {
+    fn new(call: Call, ctn: u16, pn: u16, duration: Duration) -> Self {
+        Exchange {
+            time: chrono::Local::now().to_rfc3339(),
+            call,
+            ctn,
+            pn,
+            dad: None,
+            sad: None,
+            command: None,
+            command_sha256: None,
+            response: None,
+            response_dad: None,
+            response_sad: None,
+            status: Status::OK.into(),
+            error: None,
+            duration_ms: duration.as_millis() as u64,
+        }
+    }
+
+    fn with_result<T>(mut self, result: &anyhow::Result<T>, status: impl Fn(&T) -> Status) -> Self {
+        match result {
+            Ok(value) => self.status = status(value).into(),
+            Err(why) => {
+                let status = why
+                    .downcast_ref::<http::Error>()
+                    .map_or(Status::ERR_HTSI, http::Error::status);
+                self.status = status.into();
+                self.error = Some(why.to_string());
+            }
+        }
+        self
+    }
+}
+
+/// Writes every call handled by the configured backend to `record_path`.
+pub struct Recorder;
+
+impl Backend for Recorder {
+    fn open(&self, ctn: u16, pn: u16) -> anyhow::Result<(Status, Option<Terminal>)> {
+        let started = Instant::now();
+        let result = super::selected().open(ctn, pn);
+
+        write(
+            Exchange::new(Call::Init, ctn, pn, started.elapsed())
+                .with_result(&result, |(status, _)| *status),
+        );
+        result
+    }
+
+    fn transmit(
+        &self,
+        ctn: u16,
+        terminal: &Terminal,
+        command: Command<'_>,
+    ) -> anyhow::Result<Response> {
+        let started = Instant::now();
+        let mut exchange = Exchange::new(Call::Data, ctn, terminal.pn, Duration::default());
+        exchange.dad = Some(command.dad);
+        exchange.sad = Some(command.sad);
+        let (recorded, sha256) = recorded_command(command.apdu);
+        exchange.command = Some(recorded);
+        exchange.command_sha256 = sha256;
+
+        let result = super::selected().transmit(ctn, terminal, command);
+
+        exchange.duration_ms = started.elapsed().as_millis() as u64;
+        let mut exchange = exchange.with_result(&result, |response| response.status);
+        if let Ok(response) = &result {
+            exchange.response = Some(HEXUPPER.encode(&response.apdu));
+            exchange.response_dad = Some(response.dad);
+            exchange.response_sad = Some(response.sad);
+        }
+        write(exchange);
+        result
+    }
+
+    fn close(&self, ctn: u16, terminal: &Terminal) -> anyhow::Result<Status> {
+        record_close(ctn, terminal, || super::selected().close(ctn, terminal))
+    }
+
+    fn close_until(
+        &self,
+        ctn: u16,
+        terminal: &Terminal,
+        deadline: Instant,
+    ) -> anyhow::Result<Status> {
+        record_close(ctn, terminal, || {
+            super::selected().close_until(ctn, terminal, deadline)
+        })
+    }
+}
+
+fn record_close(
+    ctn: u16,
+    terminal: &Terminal,
+    close: impl FnOnce() -> anyhow::Result<Status>,
+) -> anyhow::Result<Status> {
+    let started = Instant::now();
+    let result = close();
+
+    write(
+        Exchange::new(Call::Close, ctn, terminal.pn, started.elapsed())
+            .with_result(&result, |status| *status),
+    );
+    result
+}
+
+/// Command APDU as recorded, keeping the data field of a command carrying a PIN out of the
+/// recording, along with the hash of such a command.
+pub fn recorded_command(apdu: &[u8]) -> (String, Option<String>) {
+    if apdu.len() > 4 && apdu::carries_pin(&apdu[..4]) {
+        let hash = digest::digest(&digest::SHA256, apdu);
+        (
+            HEXUPPER.encode(&apdu[..4]),
+            Some(HEXLOWER.encode(hash.as_ref())),
+        )
+    } else {
+        (HEXUPPER.encode(apdu), None)
+    }
+}
+
+fn write(exchange: Exchange) {
+    let path = match &CONFIG.read().record_path {
+        Some(path) => path.clone(),
+        None => return,
+    };
+
+    let result = serde_json::to_string(&exchange)
+        .map_err(anyhow::Error::from)
+        .and_then(|line| {
+            let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+            writeln!(file, "{}", line)?;
+            Ok(())
+        });
+
+    if let Err(why) = result {
+        warn!(
+            "Failed to record {:?} call to {}: {}",
+            exchange.call, path, why
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{Call, Exchange};
+    use crate::ctapi::{close::close, data::data, init::init, status::Status};
+    use std::{
+        env::{remove_var, set_var},
+        fs,
+    };
+    use tempfile::tempdir;
+
+    #[test]
+    #[serial]
+    fn record_simulated_session() {
+        let folder = tempdir().unwrap();
+        let path = folder.path().join("session.jsonl");
+
+        set_var("K2_BACKEND", "sim");
+        set_var("K2_RECORD_PATH", path.to_str().unwrap());
+        crate::tests::init_config_clear_map();
+
+        let ctn = rand::random::<u16>();
+        assert_eq!(Some(Status::OK), init(ctn, 1).ok());
+
+        let mut dad = 1;
+        let mut sad = 2;
+        let command = [0x20, 0x13, 0x00, 0x80, 0x00];
+        let mut lenr = 258;
+        let mut response = [0u8; 258];
+        assert_eq!(
+            Some(Status::OK),
+            data(
+                ctn,
+                &mut dad,
+                &mut sad,
+                command.len() as u16,
+                command.as_ptr(),
+                &mut lenr,
+                response.as_mut_ptr(),
+            )
+            .ok()
+        );
+        assert_eq!(Some(Status::OK), close(ctn).ok());
+
+        let exchanges = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<Exchange>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(3, exchanges.len());
+        assert_eq!(
+            vec![Call::Init, Call::Data, Call::Close],
+            exchanges
+                .iter()
+                .map(|exchange| exchange.call)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some(String::from("2013008000")), exchanges[1].command);
+        assert_eq!(Some(String::from("8001009000")), exchanges[1].response);
+        assert_eq!(
+            (Some(2), Some(1)),
+            (exchanges[1].response_dad, exchanges[1].response_sad)
+        );
+        assert!(exchanges
+            .iter()
+            .all(|exchange| exchange.ctn == ctn && exchange.status == 0));
+
+        remove_var("K2_BACKEND");
+        remove_var("K2_RECORD_PATH");
+    }
+
+    #[test]
+    #[serial]
+    fn pin_is_not_recorded() {
+        let folder = tempdir().unwrap();
+        let path = folder.path().join("session.jsonl");
+
+        set_var("K2_BACKEND", "sim");
+        set_var("K2_RECORD_PATH", path.to_str().unwrap());
+        crate::tests::init_config_clear_map();
+
+        let verify = [
+            0x00, 0x20, 0x00, 0x81, 0x08, 0x25, 0x12, 0x34, 0x56, 0xFF, 0xFF, 0xFF, 0xFF,
+        ];
+        let transmit = |ctn: u16, command: &[u8]| {
+            let (mut dad, mut sad) = (0, 2);
+            let mut response = [0u8; 258];
+            let mut lenr = response.len() as u16;
+            data(
+                ctn,
+                &mut dad,
+                &mut sad,
+                command.len() as u16,
+                command.as_ptr(),
+                &mut lenr,
+                response.as_mut_ptr(),
+            )
+            .ok()
+        };
+
+        let ctn = rand::random::<u16>();
+        assert_eq!(Some(Status::OK), init(ctn, 1).ok());
+        assert_eq!(Some(Status::ERR_TRANS), transmit(ctn, &verify));
+        assert_eq!(Some(Status::OK), close(ctn).ok());
+
+        let recorded = fs::read_to_string(&path).unwrap();
+        assert!(!recorded.contains("25123456"));
+        let exchange = serde_json::from_str::<Exchange>(recorded.lines().nth(1).unwrap()).unwrap();
+        assert_eq!(Some(String::from("00200081")), exchange.command);
+        assert!(exchange.command_sha256.is_some());
+
+        // the same PIN matches on replay, another one diverges
+        remove_var("K2_RECORD_PATH");
+        set_var("K2_BACKEND", "replay");
+        for (copy, pin, status) in &[
+            ("same.jsonl", 0x12, Some(Status::ERR_TRANS)),
+            ("other.jsonl", 0x99, None),
+        ] {
+            let replay_path = folder.path().join(copy);
+            let _ = fs::copy(&path, &replay_path).unwrap();
+            set_var("K2_REPLAY_PATH", replay_path.to_str().unwrap());
+            crate::tests::init_config_clear_map();
+
+            let mut command = verify;
+            command[6] = *pin;
+            assert_eq!(Some(Status::OK), init(ctn, 1).ok());
+            assert_eq!(*status, transmit(ctn, &command));
+        }
+
+        remove_var("K2_BACKEND");
+        remove_var("K2_REPLAY_PATH");
+    }
+}
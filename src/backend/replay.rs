@@ -0,0 +1,245 @@
+use super::record::{self, Call, Exchange};
+use super::{Backend, Command, Response};
+use crate::ctapi::{status::Status, Terminal};
+use crate::{apdu, CONFIG};
+use antidote::Mutex;
+use data_encoding::HEXUPPER;
+use once_cell::sync::Lazy;
+use std::fs;
+
+/// Answers calls with the responses of a session recorded to `replay_path`.
+pub struct Replay;
+
+struct Session {
+    path: String,
+    exchanges: Vec<Exchange>,
+    /// Whether an exchange has already been replayed.
+    replayed: Vec<bool>,
+}
+
+static SESSION: Lazy<Mutex<Option<Session>>> = Lazy::new(|| Mutex::new(None));
+
+impl Session {
+    fn load(path: &str) -> anyhow::Result<Self> {
+        let exchanges = fs::read_to_string(path)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str::<Exchange>)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|why| format_err!("Failed to parse recorded session: {}", why))?;
+        info!("Replaying {} calls from {}.", exchanges.len(), path);
+
+        Ok(Session {
+            path: path.to_string(),
+            replayed: vec![false; exchanges.len()],
+            exchanges,
+        })
+    }
+
+    /// Takes the next recorded exchange of the card terminal, which has to match the given call.
+    fn next(
+        &mut self,
+        ctn: u16,
+        call: Call,
+        matches: impl Fn(&Exchange) -> Result<(), String>,
+    ) -> anyhow::Result<&Exchange> {
+        let index = (0..self.exchanges.len())
+            .find(|index| !self.replayed[*index] && self.exchanges[*index].ctn == ctn)
+            .ok_or_else(|| format_err!("Recorded session has no further calls for ctn {}", ctn))?;
+        self.replayed[index] = true;
+
+        let exchange = &self.exchanges[index];
+        let diverged = if exchange.call != call {
+            Err(format!("expected {:?} call, got {:?}", exchange.call, call))
+        } else {
+            matches(exchange)
+        };
+
+        match diverged {
+            Ok(()) => Ok(exchange),
+            Err(why) => {
+                error!("Diverged from recording at call {}: {}", index + 1, why);
+                bail!("Diverged from recording at call {}: {}", index + 1, why)
+            }
+        }
+    }
+}
+
+/// Runs the given function on the session from `replay_path`, loading it if necessary.
+fn with_session<T>(replay: impl FnOnce(&mut Session) -> anyhow::Result<T>) -> anyhow::Result<T> {
+    let path = CONFIG
+        .read()
+        .replay_path
+        .clone()
+        .ok_or_else(|| format_err!("No replay_path configured"))?;
+
+    let mut session = SESSION.lock();
+    if session.as_ref().is_none_or(|session| session.path != path) {
+        *session = Some(Session::load(&path)?);
+    }
+
+    match session.as_mut() {
+        Some(session) => replay(session),
+        None => unreachable!(),
+    }
+}
+
+impl Backend for Replay {
+    fn open(&self, ctn: u16, pn: u16) -> anyhow::Result<(Status, Option<Terminal>)> {
+        with_session(|session| {
+            let exchange = session.next(ctn, Call::Init, |exchange| {
+                if exchange.pn == pn {
+                    Ok(())
+                } else {
+                    Err(format!("expected pn {}, got {}", exchange.pn, pn))
+                }
+            })?;
+
+            let status = Status::from(exchange.status);
+            let terminal = match status {
+                Status::OK => Some(Terminal {
+                    pn,
+                    base_url: String::new(),
+                }),
+                _ => None,
+            };
+
+            Ok((status, terminal))
+        })
+    }
+
+    fn transmit(
+        &self,
+        ctn: u16,
+        _terminal: &Terminal,
+        command: Command<'_>,
+    ) -> anyhow::Result<Response> {
+        let (apdu, sha256) = record::recorded_command(command.apdu);
+        with_session(|session| {
+            let exchange = session.next(ctn, Call::Data, |exchange| {
+                let expected = (
+                    exchange.dad,
+                    exchange.sad,
+                    exchange.command.as_deref(),
+                    exchange.command_sha256.as_deref(),
+                );
+                let actual = (
+                    Some(command.dad),
+                    Some(command.sad),
+                    Some(apdu.as_str()),
+                    sha256.as_deref(),
+                );
+                if expected == actual {
+                    Ok(())
+                } else {
+                    let recorded = exchange
+                        .command
+                        .as_deref()
+                        .and_then(|hex| HEXUPPER.decode(hex.as_bytes()).ok())
+                        .map(|recorded| apdu::command(&recorded))
+                        .unwrap_or_default();
+                    Err(format!(
+                        "expected command {} (dad {:?}, sad {:?}), got {} (dad {}, sad {})",
+                        recorded,
+                        exchange.dad,
+                        exchange.sad,
+                        apdu::command(command.apdu),
+                        command.dad,
+                        command.sad
+                    ))
+                }
+            })?;
+
+            let response = HEXUPPER
+                .decode(exchange.response.as_deref().unwrap_or_default().as_bytes())
+                .map_err(|why| format_err!("Invalid response in recorded session: {}", why))?;
+
+            Ok(Response {
+                dad: exchange.response_dad.unwrap_or(command.sad),
+                sad: exchange.response_sad.unwrap_or(command.dad),
+                apdu: response,
+                status: Status::from(exchange.status),
+            })
+        })
+    }
+
+    fn close(&self, ctn: u16, _terminal: &Terminal) -> anyhow::Result<Status> {
+        with_session(|session| {
+            let exchange = session.next(ctn, Call::Close, |_| Ok(()))?;
+            Ok(Status::from(exchange.status))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::{
+        backend::{self, Command},
+        ctapi::{close::close, init::init, status::Status},
+    };
+    use std::{
+        env::{remove_var, set_var},
+        fs,
+    };
+    use tempfile::tempdir;
+
+    #[test]
+    #[serial]
+    fn replay_recorded_session() {
+        let folder = tempdir().unwrap();
+        let path = folder.path().join("session.jsonl");
+        fs::write(
+            &path,
+            concat!(
+                r#"{"time":"","call":"init","ctn":7,"pn":1,"status":0,"duration_ms":3}"#,
+                "\n",
+                r#"{"time":"","call":"data","ctn":7,"pn":1,"dad":1,"sad":2,"command":"2013008000","response":"8001059000","response_dad":2,"response_sad":1,"status":0,"duration_ms":5}"#,
+                "\n",
+                r#"{"time":"","call":"data","ctn":7,"pn":1,"dad":0,"sad":2,"command":"00B0820000","response":"9000","response_dad":2,"response_sad":0,"status":0,"duration_ms":5}"#,
+                "\n",
+                r#"{"time":"","call":"close","ctn":7,"pn":1,"status":0,"duration_ms":1}"#,
+                "\n",
+            ),
+        )
+        .unwrap();
+
+        set_var("K2_BACKEND", "replay");
+        set_var("K2_REPLAY_PATH", path.to_str().unwrap());
+        crate::tests::init_config_clear_map();
+
+        assert_eq!(Some(Status::OK), init(7, 1).ok());
+
+        let terminal = crate::tests::terminal(7).unwrap();
+        let transmit = |dad: u8, apdu: &[u8]| {
+            backend::current().transmit(
+                7,
+                &terminal,
+                Command {
+                    dad,
+                    sad: 2,
+                    apdu,
+                    lenr: 258,
+                },
+            )
+        };
+
+        let response = transmit(1, &[0x20, 0x13, 0x00, 0x80, 0x00]).unwrap();
+        assert_eq!((2, 1), (response.dad, response.sad));
+        assert_eq!(vec![0x80, 0x01, 0x05, 0x90, 0x00], response.apdu);
+        assert_eq!(Status::OK, response.status);
+
+        let diverged = transmit(0, &[0x00, 0xB0, 0x81, 0x00, 0x00]);
+        assert!(diverged
+            .err()
+            .unwrap()
+            .to_string()
+            .starts_with("Diverged from recording at call 3"));
+
+        assert_eq!(Some(Status::OK), close(7).ok());
+        assert!(init(7, 1).is_err());
+
+        remove_var("K2_BACKEND");
+        remove_var("K2_REPLAY_PATH");
+    }
+}
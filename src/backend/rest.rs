@@ -0,0 +1,178 @@
+use super::{Backend, Command, Response};
+use crate::apdu;
+use crate::ctapi::{status::Status, Terminal};
+use crate::http::{self, Operation};
+use crate::last_error::Code;
+use data_encoding::BASE64;
+use std::time::Instant;
+
+/// Card terminals of K2 peak, accessed via its REST API.
+pub struct Rest;
+
+#[allow(non_snake_case)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[derive(Deserialize)]
+struct DataResponse {
+    dad: u8,
+    sad: u8,
+    lenr: u16,
+    response: String,
+    #[serde(rename = "responseCode")]
+    status: i8,
+}
+
+impl Backend for Rest {
+    fn open(&self, ctn: u16, pn: u16) -> anyhow::Result<(Status, Option<Terminal>)> {
+        let path = format!("ct_init/{}/{}", ctn, pn);
+        let (base_url, response) = http::request(&path, None, Operation::Init)?;
+
+        let status = parse_status(&response)?;
+        match status {
+            Status::OK => {
+                info!("Card terminal opened on {}.", base_url);
+                Ok((status, Some(Terminal { pn, base_url })))
+            }
+            _ => Ok((status, None)),
+        }
+    }
+
+    fn transmit(
+        &self,
+        ctn: u16,
+        terminal: &Terminal,
+        command: Command<'_>,
+    ) -> anyhow::Result<Response> {
+        let json = json!({
+            "dad": command.dad,
+            "sad": command.sad,
+            "lenc": command.apdu.len(),
+            "command": BASE64.encode(command.apdu),
+            "lenr": command.lenr
+        });
+
+        let path = format!("ct_data/{}/{}", ctn, terminal.pn);
+        let response = http::request_to(&terminal.base_url, &path, Some(json), Operation::Data)?;
+
+        let json = match serde_json::from_str::<DataResponse>(&response) {
+            Ok(json) => json,
+            Err(why) => {
+                debug!("{}", why);
+                return Err(http::Error::Malformed(String::from(
+                    "Unexpected server response found in body!",
+                ))
+                .into());
+            }
+        };
+
+        let status = Status::from(json.status);
+        if !matches!(status, Status::OK) {
+            return Ok(Response {
+                dad: json.dad,
+                sad: json.sad,
+                apdu: Vec::new(),
+                status,
+            });
+        }
+
+        let apdu = match BASE64.decode(json.response.as_bytes()) {
+            Ok(content) => {
+                debug!("Decoded response field: {}", apdu::response(&content));
+                content
+            }
+            Err(why) => {
+                debug!("{}", why);
+                return Err(
+                    http::Error::Malformed(String::from("Failed to extract response.")).into(),
+                );
+            }
+        };
+
+        if json.lenr > command.lenr {
+            reject!(
+                Code::ResponseTooLarge,
+                "Server declared lenr {} exceeding buffer of {} bytes.",
+                json.lenr,
+                command.lenr
+            );
+            return Ok(Response {
+                dad: json.dad,
+                sad: json.sad,
+                apdu: Vec::new(),
+                status: Status::ERR_MEMORY,
+            });
+        }
+
+        if json.lenr as usize != apdu.len() {
+            warn!(
+                "Server declared lenr {} but sent {} bytes.",
+                json.lenr,
+                apdu.len()
+            );
+        }
+
+        Ok(Response {
+            dad: json.dad,
+            sad: json.sad,
+            apdu,
+            status,
+        })
+    }
+
+    fn close(&self, ctn: u16, terminal: &Terminal) -> anyhow::Result<Status> {
+        close(ctn, terminal, Operation::Close)
+    }
+
+    fn close_until(
+        &self,
+        ctn: u16,
+        terminal: &Terminal,
+        deadline: Instant,
+    ) -> anyhow::Result<Status> {
+        close(ctn, terminal, Operation::Shutdown(deadline))
+    }
+}
+
+fn close(ctn: u16, terminal: &Terminal, operation: Operation) -> anyhow::Result<Status> {
+    let path = format!("ct_close/{}/{}", ctn, terminal.pn);
+    let response = http::request_to(&terminal.base_url, &path, None, operation)?;
+
+    parse_status(&response)
+}
+
+fn parse_status(response: &str) -> anyhow::Result<Status> {
+    match response.parse::<i8>() {
+        Ok(status_code) => Ok(Status::from(status_code)),
+        Err(why) => {
+            debug!("{}", why);
+            Err(
+                http::Error::Malformed(String::from("Unexpected server response found in body!"))
+                    .into(),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::DataResponse;
+
+    #[test]
+    fn deserialize_response() {
+        let res: Result<DataResponse, _> = serde_json::from_str(
+            "{\"dad\":1,\"sad\":1,\"lenr\":5,\"response\":\"AQIDBAU=\",\"responseCode\":0}",
+        );
+
+        assert!(res.is_ok());
+        assert_eq!(
+            res.unwrap(),
+            DataResponse {
+                dad: 1,
+                sad: 1,
+                lenr: 5,
+                response: "AQIDBAU=".to_string(),
+                status: 0
+            }
+        );
+    }
+}
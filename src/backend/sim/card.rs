@@ -0,0 +1,581 @@
+use data_encoding::HEXUPPER_PERMISSIVE;
+use rand::RngCore;
+use std::{collections::HashSet, convert::TryFrom, fs, path::Path};
+
+/// Card image as found in a JSON or YAML file.
+#[derive(Deserialize)]
+pub struct CardImage {
+    /// Answer to reset, hex encoded.
+    atr: String,
+    #[serde(default)]
+    pins: Vec<PinImage>,
+    /// Master file with the whole file tree.
+    mf: FileImage,
+}
+
+#[derive(Deserialize)]
+struct PinImage {
+    /// Reference of the PIN as used in P2 of VERIFY.
+    id: u8,
+    value: String,
+    #[serde(default = "default_retries")]
+    retries: u8,
+}
+
+fn default_retries() -> u8 {
+    3
+}
+
+#[derive(Deserialize)]
+struct FileImage {
+    /// File identifier, hex encoded.
+    fid: Option<String>,
+    /// Application identifier of a DF, hex encoded.
+    aid: Option<String>,
+    /// Short file identifier of an EF.
+    sfi: Option<u8>,
+    /// Content of an EF, hex encoded. Files without content are DFs.
+    data: Option<String>,
+    #[serde(default)]
+    read: Access,
+    #[serde(default)]
+    update: Access,
+    #[serde(default)]
+    children: Vec<FileImage>,
+}
+
+/// Access rule of a file: `always`, `never` or `pin:<id>`.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Access {
+    #[default]
+    Always,
+    Never,
+    /// Allowed after the PIN with the given id has been verified.
+    Pin(u8),
+}
+
+impl TryFrom<String> for Access {
+    type Error = String;
+
+    fn try_from(rule: String) -> Result<Self, Self::Error> {
+        match rule.trim() {
+            "always" => Ok(Access::Always),
+            "never" => Ok(Access::Never),
+            rule => rule
+                .strip_prefix("pin:")
+                .and_then(|id| id.trim().parse::<u8>().ok())
+                .map(Access::Pin)
+                .ok_or_else(|| format!("Invalid access rule: {}", rule)),
+        }
+    }
+}
+
+impl CardImage {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let yaml = Path::new(path)
+            .extension()
+            .is_some_and(|extension| extension != "json");
+
+        if yaml {
+            serde_yaml::from_str(&content)
+                .map_err(|why| format_err!("Failed to parse card image: {}", why))
+        } else {
+            serde_json::from_str(&content)
+                .map_err(|why| format_err!("Failed to parse card image: {}", why))
+        }
+    }
+}
+
+struct File {
+    fid: Option<Vec<u8>>,
+    aid: Option<Vec<u8>>,
+    sfi: Option<u8>,
+    data: Option<Vec<u8>>,
+    read: Access,
+    update: Access,
+    children: Vec<File>,
+}
+
+impl File {
+    fn from_image(image: FileImage) -> anyhow::Result<Self> {
+        Ok(File {
+            fid: image.fid.as_deref().map(decode).transpose()?,
+            aid: image.aid.as_deref().map(decode).transpose()?,
+            sfi: image.sfi,
+            data: image.data.as_deref().map(decode).transpose()?,
+            read: image.read,
+            update: image.update,
+            children: image
+                .children
+                .into_iter()
+                .map(File::from_image)
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+
+    fn is_df(&self) -> bool {
+        self.data.is_none()
+    }
+
+    /// File control parameters returned on SELECT.
+    fn fcp(&self) -> Vec<u8> {
+        let mut fcp = vec![0x82, 0x01, if self.is_df() { 0x38 } else { 0x01 }];
+        if let Some(fid) = &self.fid {
+            fcp.extend_from_slice(&[0x83, fid.len() as u8]);
+            fcp.extend_from_slice(fid);
+        }
+        if let Some(aid) = &self.aid {
+            fcp.extend_from_slice(&[0x84, aid.len() as u8]);
+            fcp.extend_from_slice(aid);
+        }
+        if let Some(data) = &self.data {
+            fcp.extend_from_slice(&[0x80, 0x02]);
+            fcp.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        }
+
+        let mut response = vec![0x62, fcp.len() as u8];
+        response.extend(fcp);
+        response
+    }
+}
+
+fn decode(hex: &str) -> anyhow::Result<Vec<u8>> {
+    let hex = hex
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>();
+    HEXUPPER_PERMISSIVE
+        .decode(hex.as_bytes())
+        .map_err(|why| format_err!("Invalid hex value in card image: {}", why))
+}
+
+struct Pin {
+    id: u8,
+    value: String,
+    retries: u8,
+    remaining: u8,
+}
+
+/// Virtual ISO 7816 card answering command APDUs.
+pub struct Card {
+    atr: Vec<u8>,
+    mf: File,
+    pins: Vec<Pin>,
+    verified: HashSet<u8>,
+    /// Path of the current DF, as indices of children starting at the MF.
+    current_df: Vec<usize>,
+    current_ef: Option<Vec<usize>>,
+}
+
+const OK: [u8; 2] = [0x90, 0x00];
+const WRONG_LENGTH: [u8; 2] = [0x67, 0x00];
+const SECURITY_STATUS_NOT_SATISFIED: [u8; 2] = [0x69, 0x82];
+const PIN_BLOCKED: [u8; 2] = [0x69, 0x83];
+const NO_CURRENT_EF: [u8; 2] = [0x69, 0x86];
+const FILE_NOT_FOUND: [u8; 2] = [0x6A, 0x82];
+const NOT_ENOUGH_MEMORY: [u8; 2] = [0x6A, 0x84];
+const REFERENCE_NOT_FOUND: [u8; 2] = [0x6A, 0x88];
+const WRONG_PARAMETERS: [u8; 2] = [0x6B, 0x00];
+const INS_NOT_SUPPORTED: [u8; 2] = [0x6D, 0x00];
+const CLA_NOT_SUPPORTED: [u8; 2] = [0x6E, 0x00];
+
+impl Card {
+    pub fn new(image: CardImage) -> anyhow::Result<Self> {
+        Ok(Card {
+            atr: decode(&image.atr)?,
+            mf: File::from_image(image.mf)?,
+            pins: image
+                .pins
+                .into_iter()
+                .map(|pin| Pin {
+                    id: pin.id,
+                    value: pin.value,
+                    retries: pin.retries,
+                    remaining: pin.retries,
+                })
+                .collect(),
+            verified: HashSet::new(),
+            current_df: Vec::new(),
+            current_ef: None,
+        })
+    }
+
+    /// Resets the card, returns its ATR.
+    pub fn reset(&mut self) -> Vec<u8> {
+        self.verified.clear();
+        self.current_df.clear();
+        self.current_ef = None;
+        self.atr.clone()
+    }
+
+    /// Processes a command APDU, returns the response APDU including the status word.
+    pub fn process(&mut self, apdu: &[u8]) -> Vec<u8> {
+        let (header, data, ne) = match parse(apdu) {
+            Some(parsed) => parsed,
+            None => return WRONG_LENGTH.to_vec(),
+        };
+
+        if header[0] & 0xF0 != 0x00 {
+            return CLA_NOT_SUPPORTED.to_vec();
+        }
+
+        let (p1, p2) = (header[2], header[3]);
+        match header[1] {
+            0xA4 => self.select(p1, p2, data),
+            0xB0 => self.read_binary(p1, p2, ne),
+            0xD6 => self.update_binary(p1, p2, data),
+            0x20 => self.verify(p2, data),
+            0x84 => {
+                let mut challenge = vec![0; ne.unwrap_or(8)];
+                rand::thread_rng().fill_bytes(&mut challenge);
+                challenge.extend_from_slice(&OK);
+                challenge
+            }
+            _ => INS_NOT_SUPPORTED.to_vec(),
+        }
+    }
+
+    fn file(&self, path: &[usize]) -> &File {
+        path.iter()
+            .fold(&self.mf, |file, index| &file.children[*index])
+    }
+
+    fn file_mut(&mut self, path: &[usize]) -> &mut File {
+        path.iter()
+            .fold(&mut self.mf, |file, index| &mut file.children[*index])
+    }
+
+    fn select(&mut self, p1: u8, p2: u8, data: &[u8]) -> Vec<u8> {
+        let path = match p1 {
+            0x00 if data.is_empty() || data == [0x3F, 0x00] => Some(Vec::new()),
+            0x00 => self
+                .child(|file| file.fid.as_deref() == Some(data))
+                .or_else(|| {
+                    find(&self.mf, &mut Vec::new(), &|file| {
+                        file.fid.as_deref() == Some(data)
+                    })
+                }),
+            0x02 => self.child(|file| !file.is_df() && file.fid.as_deref() == Some(data)),
+            0x04 => find(&self.mf, &mut Vec::new(), &|file| {
+                file.aid.as_deref().is_some_and(|aid| aid.starts_with(data))
+            }),
+            _ => return WRONG_PARAMETERS.to_vec(),
+        };
+
+        let path = match path {
+            Some(path) => path,
+            None => return FILE_NOT_FOUND.to_vec(),
+        };
+
+        let file = self.file(&path);
+        let mut response = match p2 & 0x0C {
+            0x0C => Vec::new(),
+            _ => file.fcp(),
+        };
+
+        if file.is_df() {
+            self.current_df = path;
+            self.current_ef = None;
+        } else {
+            self.current_df = path
+                .split_last()
+                .map(|(_, df)| df.to_vec())
+                .unwrap_or_default();
+            self.current_ef = Some(path);
+        }
+
+        response.extend_from_slice(&OK);
+        response
+    }
+
+    /// Finds a child of the current DF.
+    fn child(&self, matches: impl Fn(&File) -> bool) -> Option<Vec<usize>> {
+        self.file(&self.current_df)
+            .children
+            .iter()
+            .position(matches)
+            .map(|index| {
+                let mut path = self.current_df.clone();
+                path.push(index);
+                path
+            })
+    }
+
+    /// Selects the EF addressed by P1 and P2, returns its path and the offset.
+    fn address(&mut self, p1: u8, p2: u8) -> Result<(Vec<usize>, usize), [u8; 2]> {
+        if p1 & 0x80 != 0 {
+            let sfi = p1 & 0x1F;
+            let path = self
+                .child(|file| !file.is_df() && file.sfi == Some(sfi))
+                .ok_or(FILE_NOT_FOUND)?;
+            self.current_ef = Some(path.clone());
+            Ok((path, p2 as usize))
+        } else {
+            let path = self.current_ef.clone().ok_or(NO_CURRENT_EF)?;
+            Ok((path, u16::from_be_bytes([p1 & 0x7F, p2]) as usize))
+        }
+    }
+
+    fn is_allowed(&self, access: Access) -> bool {
+        match access {
+            Access::Always => true,
+            Access::Never => false,
+            Access::Pin(id) => self.verified.contains(&id),
+        }
+    }
+
+    fn read_binary(&mut self, p1: u8, p2: u8, ne: Option<usize>) -> Vec<u8> {
+        let (path, offset) = match self.address(p1, p2) {
+            Ok(address) => address,
+            Err(status) => return status.to_vec(),
+        };
+
+        let file = self.file(&path);
+        if !self.is_allowed(file.read) {
+            return SECURITY_STATUS_NOT_SATISFIED.to_vec();
+        }
+
+        let content = file.data.as_deref().unwrap_or_default();
+        if offset > content.len() {
+            return WRONG_PARAMETERS.to_vec();
+        }
+
+        let ne = ne.unwrap_or(0);
+        let end = content.len().min(offset + ne);
+        let mut response = content[offset..end].to_vec();
+        if end - offset < ne && ne != 256 {
+            response.extend_from_slice(&[0x62, 0x82]);
+        } else {
+            response.extend_from_slice(&OK);
+        }
+        response
+    }
+
+    fn update_binary(&mut self, p1: u8, p2: u8, data: &[u8]) -> Vec<u8> {
+        let (path, offset) = match self.address(p1, p2) {
+            Ok(address) => address,
+            Err(status) => return status.to_vec(),
+        };
+
+        if !self.is_allowed(self.file(&path).update) {
+            return SECURITY_STATUS_NOT_SATISFIED.to_vec();
+        }
+
+        let content = self.file_mut(&path).data.get_or_insert_with(Vec::new);
+        if offset + data.len() > content.len() {
+            return NOT_ENOUGH_MEMORY.to_vec();
+        }
+
+        content[offset..offset + data.len()].copy_from_slice(data);
+        OK.to_vec()
+    }
+
+    fn verify(&mut self, p2: u8, data: &[u8]) -> Vec<u8> {
+        let id = p2 & 0x1F;
+        let pin = match self.pins.iter_mut().find(|pin| pin.id & 0x1F == id) {
+            Some(pin) => pin,
+            None => return REFERENCE_NOT_FOUND.to_vec(),
+        };
+
+        if pin.remaining == 0 {
+            return PIN_BLOCKED.to_vec();
+        }
+
+        if data.is_empty() {
+            return if self.verified.contains(&pin.id) {
+                OK.to_vec()
+            } else {
+                vec![0x63, 0xC0 | pin.remaining]
+            };
+        }
+
+        if decode_pin(data) == pin.value {
+            pin.remaining = pin.retries;
+            let _ = self.verified.insert(pin.id);
+            OK.to_vec()
+        } else {
+            pin.remaining -= 1;
+            match pin.remaining {
+                0 => PIN_BLOCKED.to_vec(),
+                remaining => vec![0x63, 0xC0 | remaining],
+            }
+        }
+    }
+}
+
+fn find(file: &File, path: &mut Vec<usize>, matches: &dyn Fn(&File) -> bool) -> Option<Vec<usize>> {
+    for (index, child) in file.children.iter().enumerate() {
+        path.push(index);
+        if matches(child) {
+            return Some(path.clone());
+        }
+        if let Some(found) = find(child, path, matches) {
+            return Some(found);
+        }
+        let _ = path.pop();
+    }
+
+    None
+}
+
+/// Splits a short APDU into header, command data and expected response length.
+fn parse(apdu: &[u8]) -> Option<(&[u8], &[u8], Option<usize>)> {
+    let ne = |le: u8| if le == 0 { 256 } else { le as usize };
+    match apdu.len() {
+        0..=3 => None,
+        4 => Some((apdu, &[], None)),
+        5 => Some((&apdu[..4], &[], Some(ne(apdu[4])))),
+        len => {
+            let lc = apdu[4] as usize;
+            match len - 5 {
+                rest if rest == lc => Some((&apdu[..4], &apdu[5..], None)),
+                rest if rest == lc + 1 => {
+                    Some((&apdu[..4], &apdu[5..5 + lc], Some(ne(apdu[len - 1]))))
+                }
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Decodes a format 2 PIN block, or takes the data as ASCII digits.
+fn decode_pin(data: &[u8]) -> String {
+    if data.len() == 8 && data[0] >> 4 == 0x2 {
+        let length = (data[0] & 0x0F) as usize;
+        data[1..]
+            .iter()
+            .flat_map(|byte| [byte >> 4, byte & 0x0F])
+            .take(length)
+            .map(|digit| char::from(b'0' + digit))
+            .collect()
+    } else {
+        String::from_utf8_lossy(data).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{Card, CardImage};
+    use data_encoding::HEXUPPER;
+
+    fn card(image: &str) -> Card {
+        Card::new(
+            CardImage::load(&format!("{}/cards/{}", env!("CARGO_MANIFEST_DIR"), image)).unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn process(card: &mut Card, apdu: &str) -> String {
+        HEXUPPER.encode(&card.process(&HEXUPPER.decode(apdu.as_bytes()).unwrap()))
+    }
+
+    #[test]
+    fn sample_card_images_are_valid() {
+        for image in &["egk.yaml", "hba.yaml", "smcb.json"] {
+            let mut card = card(image);
+            assert_eq!(
+                "3BD396FF81B1FE451F078081052D",
+                HEXUPPER.encode(&card.reset())
+            );
+        }
+    }
+
+    #[test]
+    fn select_and_read_binary() {
+        let mut card = card("egk.yaml");
+
+        assert_eq!("9000", process(&mut card, "00A4040C07D2760000010201"));
+        assert_eq!("9000", process(&mut card, "00A4020C02D002"));
+        assert_eq!("00000000000000009000", process(&mut card, "00B0000000"));
+        assert_eq!("00006282", process(&mut card, "00B0000604"));
+        assert_eq!("6B00", process(&mut card, "00B0000900"));
+
+        assert_eq!("6A82", process(&mut card, "00A4020C022F02"));
+        assert_eq!("9000", process(&mut card, "00A4000C00"));
+        assert_eq!(
+            "5A0A802768831100001234569000",
+            process(&mut card, "00B0820000")
+        );
+        assert_eq!(
+            "620B8201018302D00C80020020",
+            &process(&mut card, "00A4000402D00C")[..26]
+        );
+    }
+
+    #[test]
+    fn select_ef_under_root() {
+        let mut card = card("egk.yaml");
+
+        assert_eq!("9000", process(&mut card, "00A4020C022F02"));
+        assert_eq!(
+            "5A0A802768831100001234569000",
+            process(&mut card, "00B0000000")
+        );
+        assert_eq!("9000", process(&mut card, "00A4020C022F00"));
+        assert_eq!(
+            "61094F07D27600014480009000",
+            process(&mut card, "00B0000000")
+        );
+    }
+
+    #[test]
+    fn read_binary_requires_selected_ef() {
+        let mut card = card("egk.yaml");
+
+        assert_eq!("6986", process(&mut card, "00B0000000"));
+        assert_eq!("6A82", process(&mut card, "00B0850000"));
+    }
+
+    #[test]
+    fn access_rules() {
+        let mut card = card("hba.yaml");
+
+        assert_eq!("9000", process(&mut card, "00A4040C06D27600014602"));
+        assert_eq!("6982", process(&mut card, "00B0810000"));
+
+        assert_eq!("63C3", process(&mut card, "0020000100"));
+        assert_eq!("63C2", process(&mut card, "002000010826999999FFFFFFFF"));
+        assert_eq!("9000", process(&mut card, "002000010826123456FFFFFFFF"));
+        assert_eq!("00009000", process(&mut card, "00B0810000"));
+        assert_eq!("6982", process(&mut card, "00D600000101"));
+
+        let _ = card.reset();
+        assert_eq!("9000", process(&mut card, "00A4040C06D27600014602"));
+        assert_eq!("6982", process(&mut card, "00B0810000"));
+    }
+
+    #[test]
+    fn pin_is_blocked_after_retries() {
+        let mut card = card("egk.yaml");
+
+        assert_eq!("63C2", process(&mut card, "0020000106313131313131"));
+        assert_eq!("63C1", process(&mut card, "0020000106313131313131"));
+        assert_eq!("6983", process(&mut card, "0020000106313131313131"));
+        assert_eq!("6983", process(&mut card, "0020000106313233343536"));
+        assert_eq!("6A88", process(&mut card, "0020000206313233343536"));
+    }
+
+    #[test]
+    fn update_binary() {
+        let mut card = card("egk.yaml");
+
+        assert_eq!("9000", process(&mut card, "00A4040C07D2760000010201"));
+        assert_eq!("9000", process(&mut card, "00D68C0002AABB"));
+        assert_eq!("AABB30309000", process(&mut card, "00B08C0004"));
+        assert_eq!("6A84", process(&mut card, "00D68C1F02AABB"));
+        assert_eq!("6982", process(&mut card, "00D6810001AA"));
+    }
+
+    #[test]
+    fn unsupported_commands() {
+        let mut card = card("egk.yaml");
+
+        assert_eq!("6E00", process(&mut card, "80CA000000"));
+        assert_eq!("6D00", process(&mut card, "00CA000000"));
+        assert_eq!("6700", process(&mut card, "00A4"));
+        assert_eq!(10, card.process(&[0x00, 0x84, 0x00, 0x00, 0x08]).len());
+    }
+}
@@ -0,0 +1,301 @@
+mod card;
+
+use self::card::{Card, CardImage};
+use super::{Backend, Command, Response};
+use crate::ctapi::{status::Status, Terminal};
+use crate::last_error::Code;
+use crate::CONFIG;
+use antidote::Mutex;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+
+/// Virtual card terminals answering APDUs in process, for development without K2.
+pub struct Sim;
+
+/// Card terminal with a single slot holding the card from the configured image.
+struct VirtualTerminal {
+    card: Option<Card>,
+    activated: bool,
+}
+
+static TERMINALS: Lazy<Mutex<HashMap<u16, VirtualTerminal>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+const CT: u8 = 1;
+const ICC: u8 = 0;
+
+const OK: [u8; 2] = [0x90, 0x00];
+const OK_ASYNCHRONOUS: [u8; 2] = [0x90, 0x01];
+const NO_CARD: [u8; 2] = [0x62, 0x00];
+const CARD_ALREADY_ACTIVATED: [u8; 2] = [0x62, 0x01];
+const RESET_FAILED: [u8; 2] = [0x64, 0x00];
+const WRONG_PARAMETERS: [u8; 2] = [0x6A, 0x00];
+const INS_NOT_SUPPORTED: [u8; 2] = [0x6D, 0x00];
+const CLA_NOT_SUPPORTED: [u8; 2] = [0x6E, 0x00];
+
+impl Backend for Sim {
+    fn open(&self, ctn: u16, pn: u16) -> anyhow::Result<(Status, Option<Terminal>)> {
+        let card = match &CONFIG.read().sim_card_image {
+            Some(path) => Some(Card::new(CardImage::load(path)?)?),
+            None => None,
+        };
+
+        let _ = TERMINALS.lock().insert(
+            ctn,
+            VirtualTerminal {
+                card,
+                activated: false,
+            },
+        );
+        info!("Simulated card terminal opened.");
+
+        Ok((
+            Status::OK,
+            Some(Terminal {
+                pn,
+                base_url: String::new(),
+            }),
+        ))
+    }
+
+    fn transmit(
+        &self,
+        ctn: u16,
+        _terminal: &Terminal,
+        command: Command<'_>,
+    ) -> anyhow::Result<Response> {
+        let mut terminals = TERMINALS.lock();
+        let terminal = terminals
+            .get_mut(&ctn)
+            .ok_or_else(|| format_err!("Simulated card terminal {} is not open", ctn))?;
+
+        let (apdu, status) = match command.dad {
+            CT => (terminal.ctbcs(command.apdu), Status::OK),
+            ICC => match terminal.card.as_mut() {
+                Some(card) if terminal.activated => (card.process(command.apdu), Status::OK),
+                _ => {
+                    reject!(
+                        Code::Status,
+                        "No activated card in simulated card terminal."
+                    );
+                    (Vec::new(), Status::ERR_TRANS)
+                }
+            },
+            dad => {
+                reject!(Code::InvalidCall, "Unknown destination address {}.", dad);
+                (Vec::new(), Status::ERR_INVALID)
+            }
+        };
+
+        Ok(Response {
+            dad: command.sad,
+            sad: command.dad,
+            apdu,
+            status,
+        })
+    }
+
+    fn close(&self, ctn: u16, _terminal: &Terminal) -> anyhow::Result<Status> {
+        let _ = TERMINALS.lock().remove(&ctn);
+        Ok(Status::OK)
+    }
+}
+
+impl VirtualTerminal {
+    /// Processes a CT-BCS command addressed to the terminal itself.
+    fn ctbcs(&mut self, apdu: &[u8]) -> Vec<u8> {
+        if apdu.len() < 4 {
+            return WRONG_PARAMETERS.to_vec();
+        }
+
+        if apdu[0] != 0x20 {
+            return CLA_NOT_SUPPORTED.to_vec();
+        }
+
+        let (p1, p2) = (apdu[2], apdu[3]);
+        match apdu[1] {
+            // RESET CT
+            0x11 if p1 == 0x00 => {
+                self.activated = false;
+                OK.to_vec()
+            }
+            0x11 if p1 == 0x01 => match self.card.as_mut() {
+                Some(card) => {
+                    self.activated = true;
+                    with_atr(card.reset(), p2)
+                }
+                None => RESET_FAILED.to_vec(),
+            },
+            // REQUEST ICC
+            0x12 if p1 == 0x01 => match self.card.as_mut() {
+                Some(_) if self.activated => CARD_ALREADY_ACTIVATED.to_vec(),
+                Some(card) => {
+                    self.activated = true;
+                    with_atr(card.reset(), p2)
+                }
+                None => NO_CARD.to_vec(),
+            },
+            // GET STATUS
+            0x13 if p1 == 0x00 && p2 == 0x46 => {
+                let mut response = vec![0x46, 0x05];
+                response.extend_from_slice(b"SIM01");
+                response.extend_from_slice(&OK);
+                response
+            }
+            0x13 if p1 == 0x00 && p2 == 0x80 => {
+                let status = match (&self.card, self.activated) {
+                    (None, _) => 0x00,
+                    (Some(_), false) => 0x03,
+                    (Some(_), true) => 0x05,
+                };
+                vec![0x80, 0x01, status, 0x90, 0x00]
+            }
+            // EJECT ICC, the card is presented again on the next REQUEST ICC
+            0x15 if p1 == 0x01 => {
+                self.activated = false;
+                OK.to_vec()
+            }
+            0x11 | 0x12 | 0x13 | 0x15 => WRONG_PARAMETERS.to_vec(),
+            _ => INS_NOT_SUPPORTED.to_vec(),
+        }
+    }
+}
+
+/// Response to a reset, with the ATR if requested in P2.
+fn with_atr(atr: Vec<u8>, p2: u8) -> Vec<u8> {
+    let mut response = match p2 & 0x0F {
+        0x01 => atr,
+        _ => Vec::new(),
+    };
+    response.extend_from_slice(&OK_ASYNCHRONOUS);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::{
+        backend::{self, Command},
+        ctapi::{init::init, status::Status},
+    };
+    use data_encoding::HEXUPPER;
+    use std::env::{remove_var, set_var};
+
+    fn transmit(ctn: u16, dad: u8, apdu: &str) -> (u8, u8, String, Status) {
+        let terminal = crate::tests::terminal(ctn).unwrap();
+        let apdu = HEXUPPER.decode(apdu.as_bytes()).unwrap();
+        let response = backend::current()
+            .transmit(
+                ctn,
+                &terminal,
+                Command {
+                    dad,
+                    sad: 2,
+                    apdu: &apdu,
+                    lenr: 258,
+                },
+            )
+            .unwrap();
+
+        (
+            response.dad,
+            response.sad,
+            HEXUPPER.encode(&response.apdu),
+            response.status,
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn card_terminal_commands() {
+        set_var("K2_BACKEND", "sim");
+        set_var(
+            "K2_SIM_CARD_IMAGE",
+            format!("{}/cards/egk.yaml", env!("CARGO_MANIFEST_DIR")),
+        );
+        crate::tests::init_config_clear_map();
+
+        let ctn = rand::random::<u16>();
+        assert_eq!(Some(Status::OK), init(ctn, 1).ok());
+
+        assert_eq!(
+            (2, 1, String::from("8001039000"), Status::OK),
+            transmit(ctn, 1, "2013008000")
+        );
+        assert_eq!(
+            (2, 0, String::new(), Status::ERR_TRANS),
+            transmit(ctn, 0, "00A4000C00")
+        );
+        assert_eq!(
+            (
+                2,
+                1,
+                String::from("3BD396FF81B1FE451F078081052D9001"),
+                Status::OK
+            ),
+            transmit(ctn, 1, "2012010100")
+        );
+        assert_eq!(
+            (2, 1, String::from("6201"), Status::OK),
+            transmit(ctn, 1, "2012010100")
+        );
+        assert_eq!(
+            (2, 1, String::from("8001059000"), Status::OK),
+            transmit(ctn, 1, "2013008000")
+        );
+        assert_eq!(
+            (
+                2,
+                0,
+                String::from("5A0A802768831100001234569000"),
+                Status::OK
+            ),
+            transmit(ctn, 0, "00B0820000")
+        );
+        assert_eq!(
+            (2, 1, String::from("9000"), Status::OK),
+            transmit(ctn, 1, "20150100")
+        );
+        assert_eq!(
+            (2, 0, String::new(), Status::ERR_TRANS),
+            transmit(ctn, 0, "00B0820000")
+        );
+        assert_eq!(
+            (2, 1, String::from("9001"), Status::OK),
+            transmit(ctn, 1, "20110100")
+        );
+        assert_eq!(
+            (2, 1, String::from("460553494D30319000"), Status::OK),
+            transmit(ctn, 1, "2013004600")
+        );
+        assert_eq!(
+            (2, 1, String::from("6D00"), Status::OK),
+            transmit(ctn, 1, "20FF0000")
+        );
+        assert_eq!(Status::ERR_INVALID, transmit(ctn, 5, "20150100").3);
+
+        remove_var("K2_BACKEND");
+        remove_var("K2_SIM_CARD_IMAGE");
+    }
+
+    #[test]
+    #[serial]
+    fn card_terminal_without_card() {
+        set_var("K2_BACKEND", "sim");
+        crate::tests::init_config_clear_map();
+
+        let ctn = rand::random::<u16>();
+        assert_eq!(Some(Status::OK), init(ctn, 1).ok());
+
+        assert_eq!(
+            (2, 1, String::from("6200"), Status::OK),
+            transmit(ctn, 1, "2012010100")
+        );
+        assert_eq!(
+            (2, 1, String::from("8001009000"), Status::OK),
+            transmit(ctn, 1, "2013008000")
+        );
+
+        remove_var("K2_BACKEND");
+    }
+}
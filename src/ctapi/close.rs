@@ -1,48 +1,127 @@
-use crate::ctapi::MAP;
-use crate::{http, Status, CONFIG};
+use crate::backend;
+use crate::ctapi::{self, journal, MAP};
+use crate::last_error::Code;
+use crate::{Status, CONFIG};
+use once_cell::sync::Lazy;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 pub fn close(mut ctn: u16) -> anyhow::Result<Status> {
-    if let Some(ctn_from_cfg) = CONFIG.read().ctn {
+    if let Some((ctn_from_cfg, _)) = ctapi::mapped(ctn) {
         debug!("Use ctn '{}' from configuration", ctn_from_cfg);
         ctn = ctn_from_cfg;
     }
 
-    if !MAP.read().contains_key(&ctn) {
-        error!("Card terminal has not been opened.");
-        return Ok(Status::ERR_INVALID);
-    }
+    let session = match ctapi::session(ctn) {
+        Some(session) => session,
+        None => {
+            reject!(Code::InvalidCall, "Card terminal has not been opened.");
+            return Ok(Status::ERR_INVALID);
+        }
+    };
 
-    let pn = match MAP.read().get(&ctn) {
-        None => return Err(format_err!("Failed to extract pn for given ctn!")),
-        Some(pn) => *pn,
+    // wait for running calls on the ctn
+    let mut terminal = session.terminal.lock();
+    let status = match terminal.as_ref() {
+        Some(terminal) => backend::current().close(ctn, terminal)?,
+        None => {
+            reject!(Code::InvalidCall, "Card terminal has not been opened.");
+            return Ok(Status::ERR_INVALID);
+        }
     };
 
-    let path = format!("ct_close/{}/{}", ctn, pn);
-    let response = http::request(&path, None)?;
+    if let Status::OK = status {
+        // Remove CTN
+        *terminal = None;
+        let _ = MAP.write().remove(&ctn);
+        journal::closed(ctn);
+        info!("Card terminal closed.");
+    }
 
-    match response.parse::<i8>() {
-        Ok(status_code) => {
-            let status = Status::from(status_code);
-            if let Status::OK = status {
-                // Remove CTN
-                let _ = MAP.write().remove(&ctn);
-                info!("Card terminal closed.");
-            }
+    Ok(status)
+}
+
+/// Closes every card terminal the host left open within `shutdown_timeout`, on unload or
+/// `K2_shutdown`. Returns the number of card terminals that are still open.
+pub fn close_all() -> usize {
+    // nothing has been opened if the library has not been used at all
+    if Lazy::get(&MAP).is_none() {
+        return 0;
+    }
 
-            Ok(status)
+    let open = MAP
+        .read()
+        .iter()
+        .map(|(ctn, session)| (*ctn, Arc::clone(session)))
+        .collect::<Vec<_>>();
+    let timeout = Duration::from_millis(CONFIG.read().shutdown_timeout);
+    if open.is_empty() || timeout == Duration::default() {
+        return open.len();
+    }
+
+    // a single attempt per terminal, the host is waiting for us to go away
+    let deadline = Instant::now() + timeout;
+    let mut left_open = 0;
+
+    for (ctn, session) in open {
+        if Instant::now() >= deadline {
+            warn!(
+                "Timed out closing card terminals, ctn {} is left open.",
+                ctn
+            );
+            left_open += 1;
+            continue;
         }
-        Err(why) => {
-            debug!("{}", why);
-            Err(format_err!("Unexpected server response found in body!"))
+
+        // a call still running on another thread keeps its terminal
+        let mut terminal = match session.terminal.try_lock() {
+            Ok(terminal) => terminal,
+            Err(_) => {
+                warn!("Card terminal {} is busy and left open.", ctn);
+                left_open += 1;
+                continue;
+            }
+        };
+
+        let status = match terminal.as_ref() {
+            Some(open) => backend::current().close_until(ctn, open, deadline),
+            None => continue,
+        };
+        match status {
+            Ok(Status::OK) => {
+                *terminal = None;
+                let _ = MAP.write().remove(&ctn);
+                journal::closed(ctn);
+                info!("Card terminal {} closed on shutdown.", ctn);
+            }
+            Ok(status) => {
+                warn!(
+                    "Closing card terminal {} on shutdown returned status {}.",
+                    ctn,
+                    i8::from(status)
+                );
+                left_open += 1;
+            }
+            Err(why) => {
+                warn!(
+                    "Failed to close card terminal {} on shutdown: {:#}",
+                    ctn, why
+                );
+                left_open += 1;
+            }
         }
     }
+
+    left_open
 }
 
 #[cfg(test)]
 mod tests {
 
-    use super::close;
-    use crate::{ctapi::MAP, Status};
+    use super::{close, close_all};
+    use crate::{ctapi::MAP, Status, CONFIG};
     use std::env::{remove_var, set_var};
     use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
 
@@ -55,7 +134,7 @@ mod tests {
         let ctn = rand::random::<u16>();
         let pn = rand::random::<u16>();
 
-        let _ = MAP.write().insert(ctn, pn);
+        crate::tests::insert_terminal(ctn, pn);
 
         assert!(close(ctn).is_err());
         remove_var("K2_BASE_URL");
@@ -75,7 +154,7 @@ mod tests {
 
         let ctn = rand::random::<u16>();
         let pn = rand::random::<u16>();
-        let _ = MAP.write().insert(ctn, pn);
+        crate::tests::insert_terminal(ctn, pn);
 
         let mock_server = MockServer::start().await;
         let mock = Mock::given(matchers::path(format!("/ct_close/{}/{}", ctn, pn)))
@@ -98,7 +177,7 @@ mod tests {
         set_var("K2_PN", format!("{}", pn));
 
         crate::tests::init_config_clear_map();
-        let _ = MAP.write().insert(ctn, pn);
+        crate::tests::insert_terminal(ctn, pn);
 
         let mock_server = MockServer::start().await;
         let mock = Mock::given(matchers::path(format!("/ct_close/{}/{}", ctn, pn)))
@@ -122,7 +201,7 @@ mod tests {
 
         let ctn = rand::random::<u16>();
         let pn = rand::random::<u16>();
-        let _ = MAP.write().insert(ctn, pn);
+        crate::tests::insert_terminal(ctn, pn);
 
         let mock_server = MockServer::start().await;
         Mock::given(matchers::any())
@@ -151,7 +230,7 @@ mod tests {
 
         let ctn = rand::random::<u16>();
         let pn = rand::random::<u16>();
-        let _ = MAP.write().insert(ctn, pn);
+        crate::tests::insert_terminal(ctn, pn);
 
         assert!(close(ctn).is_err());
         assert!(MAP.read().contains_key(&ctn));
@@ -173,7 +252,7 @@ mod tests {
 
         let ctn = rand::random::<u16>();
         let pn = rand::random::<u16>();
-        let _ = MAP.write().insert(ctn, pn);
+        crate::tests::insert_terminal(ctn, pn);
 
         assert_eq!(Some(Status::ERR_MEMORY), close(ctn).ok());
         assert!(MAP.read().contains_key(&ctn));
@@ -195,11 +274,72 @@ mod tests {
 
         let ctn = rand::random::<u16>();
         let pn = rand::random::<u16>();
-        let _ = MAP.write().insert(ctn, pn);
+        crate::tests::insert_terminal(ctn, pn);
 
         assert_eq!(Some(Status::OK), close(ctn).ok());
         assert!(!MAP.read().contains_key(&ctn));
 
         remove_var("K2_BASE_URL");
     }
+
+    #[async_std::test]
+    #[serial]
+    async fn close_is_retried_after_server_error() {
+        let mock_server = MockServer::start().await;
+        Mock::given(matchers::any())
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(matchers::any())
+            .respond_with(ResponseTemplate::new(200).set_body_json(0))
+            .mount(&mock_server)
+            .await;
+        set_var("K2_BASE_URL", mock_server.uri());
+        set_var("K2_RETRY_ATTEMPTS", "2");
+        set_var("K2_RETRY_BACKOFF", "1");
+
+        crate::tests::init_config_clear_map();
+
+        let ctn = rand::random::<u16>();
+        let pn = rand::random::<u16>();
+        crate::tests::insert_terminal(ctn, pn);
+
+        assert_eq!(Some(Status::OK), close(ctn).ok());
+        assert_eq!(
+            Some(2),
+            mock_server.received_requests().await.map(|r| r.len())
+        );
+
+        remove_var("K2_BASE_URL");
+        remove_var("K2_RETRY_ATTEMPTS");
+        remove_var("K2_RETRY_BACKOFF");
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn close_all_makes_a_single_attempt() {
+        let mock_server = MockServer::start().await;
+        Mock::given(matchers::any())
+            .respond_with(ResponseTemplate::new(503))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+        set_var("K2_BASE_URL", mock_server.uri());
+        set_var("K2_RETRY_ATTEMPTS", "3");
+        set_var("K2_RETRY_BACKOFF", "1");
+
+        crate::tests::init_config_clear_map();
+        crate::tests::insert_terminal(1, 11);
+        crate::tests::insert_terminal(2, 12);
+
+        assert_eq!(2, close_all());
+        assert_eq!(2, MAP.read().len());
+        assert_eq!(3, CONFIG.read().retry_attempts);
+        assert_eq!(None, CONFIG.read().close_timeout);
+
+        remove_var("K2_BASE_URL");
+        remove_var("K2_RETRY_ATTEMPTS");
+        remove_var("K2_RETRY_BACKOFF");
+    }
 }
@@ -1,20 +1,11 @@
-use crate::ctapi::MAP;
-use crate::{http, Status, CONFIG};
-use data_encoding::{BASE64, HEXLOWER};
+use crate::apdu;
+use crate::backend::{self, Command};
+use crate::ctapi::{self, journal, Terminal};
+use crate::http;
+use crate::last_error::Code;
+use crate::{Status, CONFIG};
 use std::slice;
 
-#[allow(non_snake_case)]
-#[cfg_attr(test, derive(Debug, PartialEq))]
-#[derive(Deserialize)]
-struct Response {
-    dad: u8,
-    sad: u8,
-    lenr: u16,
-    response: String,
-    #[serde(rename = "responseCode")]
-    status: i8,
-}
-
 pub fn data(
     mut ctn: u16,
     dad: *mut u8,
@@ -24,19 +15,27 @@ pub fn data(
     lenr: *mut u16,
     response: *mut u8,
 ) -> anyhow::Result<Status> {
-    if let Some(ctn_from_cfg) = CONFIG.read().ctn {
+    if let Some((ctn_from_cfg, _)) = ctapi::mapped(ctn) {
         debug!("Use ctn '{}' from configuration", ctn_from_cfg);
         ctn = ctn_from_cfg;
     }
 
-    if !MAP.read().contains_key(&ctn) {
-        error!("Card terminal has not been opened.");
-        return Ok(Status::ERR_INVALID);
-    }
+    let session = match ctapi::session(ctn) {
+        Some(session) => session,
+        None => {
+            reject!(Code::InvalidCall, "Card terminal has not been opened.");
+            return Ok(Status::ERR_INVALID);
+        }
+    };
 
-    let pn = match MAP.read().get(&ctn) {
-        None => return Err(format_err!("Failed to extract pn for given ctn!")),
-        Some(pn) => *pn,
+    // calls on the ctn are serialized, while other ctns proceed in parallel
+    let mut terminal = session.terminal.lock();
+    let current = match terminal.as_ref() {
+        Some(terminal) => terminal,
+        None => {
+            reject!(Code::InvalidCall, "Card terminal has not been opened.");
+            return Ok(Status::ERR_INVALID);
+        }
     };
 
     let safe_dad: &mut u8 = unsafe { &mut *dad };
@@ -47,7 +46,7 @@ pub fn data(
     debug!("lenc: {}", lenc);
 
     let safe_command = unsafe { slice::from_raw_parts(command, lenc as usize) };
-    debug!("command: {:?}", HEXLOWER.encode(safe_command));
+    debug!("command: {}", apdu::command(safe_command));
 
     let safe_lenr: &mut u16 = unsafe { &mut *lenr };
     debug!("lenr: {}", safe_lenr);
@@ -55,45 +54,76 @@ pub fn data(
     let safe_response = unsafe { slice::from_raw_parts_mut(response, *safe_lenr as usize) };
     debug!("response with {} slices formed", safe_response.len());
 
-    let json = json!({
-        "dad": *safe_dad,
-        "sad": *safe_sad,
-        "lenc": lenc,
-        "command": BASE64.encode(safe_command),
-        "lenr": *safe_lenr
-    });
+    let command = Command {
+        dad: *safe_dad,
+        sad: *safe_sad,
+        apdu: safe_command,
+        lenr: *safe_lenr,
+    };
+    let response = match backend::current().transmit(ctn, current, command) {
+        Err(why) if session_lost(&why) => return reopen(ctn, session.pn, &mut terminal).ok_or(why),
+        result => result?,
+    };
+    debug!("{}", apdu::trace(*safe_dad, safe_command, &response.apdu));
+
+    if let Status::OK = response.status {
+        if response.apdu.len() > safe_response.len() {
+            reject!(
+                Code::ResponseTooLarge,
+                "Response with {} bytes exceeds buffer of {} bytes.",
+                response.apdu.len(),
+                safe_response.len()
+            );
+            return Ok(Status::ERR_MEMORY);
+        }
 
-    let path = format!("ct_data/{}/{}", ctn, pn);
-    let response = http::request(&path, Some(json))?;
+        safe_response[..response.apdu.len()].copy_from_slice(&response.apdu);
 
-    match serde_json::from_str::<Response>(&response) {
-        Err(why) => {
-            debug!("{}", why);
-            Err(format_err!("Unexpected server response found in body!"))
+        *safe_dad = response.dad;
+        *safe_sad = response.sad;
+        *safe_lenr = response.apdu.len() as u16;
+    }
+
+    Ok(response.status)
+}
+
+/// K2 forgets its sessions on restart, which it signals by one of `session_lost_status_codes`,
+/// by default 404 or 410 for a card terminal it has opened before.
+fn session_lost(why: &anyhow::Error) -> bool {
+    match why.downcast_ref::<http::Error>() {
+        Some(http::Error::Status(code, _)) => {
+            CONFIG.read().session_lost_status_codes.contains(code)
         }
-        Ok(json) => {
-            let status = Status::from(json.status);
-            if let Status::OK = status {
-                let decoded = match BASE64.decode(&json.response.into_bytes()) {
-                    Ok(content) => {
-                        debug!("Decoded response field: {:?}", HEXLOWER.encode(&content));
-                        content
-                    }
-                    Err(why) => {
-                        debug!("{}", why);
-                        return Err(format_err!("Failed to extract response."));
-                    }
-                };
-
-                for (place, element) in safe_response.iter_mut().zip(decoded.iter()) {
-                    *place = *element;
-                }
-
-                *safe_dad = json.dad;
-                *safe_sad = json.sad;
-                *safe_lenr = json.lenr;
-            }
-            Ok(status)
+        _ => false,
+    }
+}
+
+/// Opens the card terminal again after its session has been lost. The card has been reset
+/// meanwhile, so the command is not sent and the call fails with `status_on_session_lost`.
+/// Returns `None` if it cannot be opened, as K2 has not just forgotten the session then.
+fn reopen(ctn: u16, pn: u16, terminal: &mut Option<Terminal>) -> Option<Status> {
+    warn!("Session of card terminal may be lost, opening it again.");
+
+    match backend::current().open(ctn, pn) {
+        Ok((Status::OK, Some(opened))) => {
+            journal::opened(ctn, &opened);
+            *terminal = Some(opened);
+            reject!(
+                Code::SessionLost,
+                "Session lost, card terminal opened again and card reset."
+            );
+            Some(CONFIG.read().status_on_session_lost)
+        }
+        Ok((status, _)) => {
+            warn!(
+                "Opening card terminal again returned status {}.",
+                i8::from(status)
+            );
+            None
+        }
+        Err(why) => {
+            warn!("Opening card terminal again failed: {:#}", why);
+            None
         }
     }
 }
@@ -101,36 +131,21 @@ pub fn data(
 #[cfg(test)]
 mod tests {
 
-    use super::{data, Response};
-    use crate::{ctapi::MAP, Status};
+    use super::data;
+    use crate::{
+        ctapi::Terminal,
+        last_error::{self, Code},
+        Status,
+    };
     use data_encoding::BASE64;
     use serde_json::{self, json, Value};
     use std::{
         env::{remove_var, set_var},
-        slice,
-        u16::MAX,
+        slice, thread,
+        time::{Duration, Instant},
     };
     use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
 
-    #[test]
-    fn deserialize_response() {
-        let res: Result<Response, _> = serde_json::from_str(
-            "{\"dad\":1,\"sad\":1,\"lenr\":5,\"response\":\"AQIDBAU=\",\"responseCode\":0}",
-        );
-
-        assert!(res.is_ok());
-        assert_eq!(
-            res.unwrap(),
-            Response {
-                dad: 1,
-                sad: 1,
-                lenr: 5,
-                response: "AQIDBAU=".to_string(),
-                status: 0
-            }
-        );
-    }
-
     #[test]
     fn returns_err_invalid_if_terminal_closed() {
         let (_, command, lenc, response, mut lenr, mut dad, mut sad, ctn, _) = rand_params();
@@ -148,7 +163,7 @@ mod tests {
         crate::tests::init_config_clear_map();
 
         let (_, command, lenc, response, mut lenr, mut dad, mut sad, ctn, pn) = rand_params();
-        let _ = MAP.write().insert(ctn, pn);
+        crate::tests::insert_terminal(ctn, pn);
 
         assert!(data(ctn, &mut dad, &mut sad, lenc, command, &mut lenr, response).is_err());
 
@@ -159,7 +174,7 @@ mod tests {
     #[serial]
     async fn use_ctn_and_pn_in_request_path() {
         let (_, command, lenc, response, mut lenr, mut dad, mut sad, ctn, pn) = rand_params();
-        let _ = MAP.write().insert(ctn, pn);
+        crate::tests::insert_terminal(ctn, pn);
 
         let mock_server = MockServer::start().await;
         Mock::given(matchers::path(format!("/ct_data/{}/{}", ctn, pn)))
@@ -189,7 +204,7 @@ mod tests {
 
         let (command, command_ptr, lenc, response, mut lenr, mut dad, mut sad, ctn, pn) =
             rand_params();
-        let _ = MAP.write().insert(ctn, pn);
+        crate::tests::insert_terminal(ctn, pn);
 
         let _ = data(
             ctn,
@@ -245,7 +260,7 @@ mod tests {
         crate::tests::init_config_clear_map();
 
         let (_, command, lenc, response, mut lenr, mut dad, mut sad, ctn, pn) = rand_params();
-        let _ = MAP.write().insert(ctn, pn);
+        crate::tests::insert_terminal(ctn, pn);
 
         let _ = data(ctn, &mut dad, &mut sad, lenc, command, &mut lenr, response);
 
@@ -259,6 +274,209 @@ mod tests {
         remove_var("K2_BASE_URL");
     }
 
+    #[async_std::test]
+    #[serial]
+    async fn response_fits_buffer_exactly() {
+        let mock_server = MockServer::start().await;
+        Mock::given(matchers::any())
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "dad":39,
+                "sad":63,
+                "lenr":5,
+                "response":"AQIDBAU=",
+                "responseCode":0
+            })))
+            .mount(&mock_server)
+            .await;
+        set_var("K2_BASE_URL", mock_server.uri());
+
+        crate::tests::init_config_clear_map();
+
+        let (ctn, pn) = (rand::random::<u16>(), rand::random::<u16>());
+        let command = [0x00, 0xB0, 0x00, 0x00, 0x00];
+        let mut response = [0xAAu8; 16];
+        let (mut dad, mut sad) = (0, 2);
+        crate::tests::insert_terminal(ctn, pn);
+        let mut lenr: u16 = 5;
+
+        assert_eq!(
+            Some(Status::OK),
+            data(
+                ctn,
+                &mut dad,
+                &mut sad,
+                command.len() as u16,
+                command.as_ptr(),
+                &mut lenr,
+                response.as_mut_ptr(),
+            )
+            .ok()
+        );
+        assert_eq!(5, lenr);
+
+        let slice = &response[..lenr as usize];
+        assert_eq!([1, 2, 3, 4, 5], slice);
+
+        remove_var("K2_BASE_URL");
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn returns_err_memory_if_response_exceeds_buffer() {
+        let mock_server = MockServer::start().await;
+        Mock::given(matchers::any())
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "dad":39,
+                "sad":63,
+                "lenr":5,
+                "response":"AQIDBAU=",
+                "responseCode":0
+            })))
+            .mount(&mock_server)
+            .await;
+        set_var("K2_BASE_URL", mock_server.uri());
+
+        crate::tests::init_config_clear_map();
+
+        let (ctn, pn) = (rand::random::<u16>(), rand::random::<u16>());
+        let command = [0x00, 0xB0, 0x00, 0x00, 0x00];
+        let mut response = [0xAAu8; 16];
+        let (mut dad, mut sad) = (0, 2);
+        crate::tests::insert_terminal(ctn, pn);
+        let mut lenr: u16 = 4;
+        let (orig_dad, orig_sad) = (dad, sad);
+        let before = response[..5].to_vec();
+        last_error::begin();
+
+        assert_eq!(
+            Some(Status::ERR_MEMORY),
+            data(
+                ctn,
+                &mut dad,
+                &mut sad,
+                command.len() as u16,
+                command.as_ptr(),
+                &mut lenr,
+                response.as_mut_ptr(),
+            )
+            .ok()
+        );
+        assert_eq!(4, lenr);
+        assert_eq!(orig_dad, dad);
+        assert_eq!(orig_sad, sad);
+        assert_eq!(before, response[..5]);
+        last_error::finish(ctn, "CT_data", Status::ERR_MEMORY.into());
+        assert_eq!(Code::ResponseTooLarge, last_error::code());
+
+        remove_var("K2_BASE_URL");
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn returns_err_memory_if_declared_lenr_exceeds_buffer() {
+        let mock_server = MockServer::start().await;
+        Mock::given(matchers::any())
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "dad":39,
+                "sad":63,
+                "lenr":300,
+                "response":"kAA=",
+                "responseCode":0
+            })))
+            .mount(&mock_server)
+            .await;
+        set_var("K2_BASE_URL", mock_server.uri());
+
+        crate::tests::init_config_clear_map();
+
+        let (ctn, pn) = (rand::random::<u16>(), rand::random::<u16>());
+        let command = [0x00, 0xB0, 0x00, 0x00, 0x00];
+        let mut response = [0xAAu8; 16];
+        let (mut dad, mut sad) = (0, 2);
+        crate::tests::insert_terminal(ctn, pn);
+        let mut lenr: u16 = 2;
+        let before = response[..2].to_vec();
+        last_error::begin();
+
+        assert_eq!(
+            Some(Status::ERR_MEMORY),
+            data(
+                ctn,
+                &mut dad,
+                &mut sad,
+                command.len() as u16,
+                command.as_ptr(),
+                &mut lenr,
+                response.as_mut_ptr(),
+            )
+            .ok()
+        );
+        assert_eq!(2, lenr);
+        assert_eq!(before, response[..2]);
+        last_error::finish(ctn, "CT_data", Status::ERR_MEMORY.into());
+        assert_eq!(Code::ResponseTooLarge, last_error::code());
+
+        remove_var("K2_BASE_URL");
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn lenr_is_set_to_decoded_length_if_server_understates_it() {
+        let mock_server = MockServer::start().await;
+        Mock::given(matchers::any())
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "dad":39,
+                "sad":63,
+                "lenr":2,
+                "response":"AQIDBAU=",
+                "responseCode":0
+            })))
+            .mount(&mock_server)
+            .await;
+        set_var("K2_BASE_URL", mock_server.uri());
+
+        crate::tests::init_config_clear_map();
+
+        let (ctn, pn) = (rand::random::<u16>(), rand::random::<u16>());
+        let command = [0x00, 0xB0, 0x00, 0x00, 0x00];
+        let mut response = [0xAAu8; 16];
+        let (mut dad, mut sad) = (0, 2);
+        crate::tests::insert_terminal(ctn, pn);
+        let mut lenr: u16 = 4;
+
+        assert_eq!(
+            Some(Status::ERR_MEMORY),
+            data(
+                ctn,
+                &mut dad,
+                &mut sad,
+                command.len() as u16,
+                command.as_ptr(),
+                &mut lenr,
+                response.as_mut_ptr(),
+            )
+            .ok()
+        );
+
+        lenr = 10;
+        assert_eq!(
+            Some(Status::OK),
+            data(
+                ctn,
+                &mut dad,
+                &mut sad,
+                command.len() as u16,
+                command.as_ptr(),
+                &mut lenr,
+                response.as_mut_ptr(),
+            )
+            .ok()
+        );
+        assert_eq!(5, lenr);
+
+        remove_var("K2_BASE_URL");
+    }
+
     #[async_std::test]
     #[serial]
     #[should_panic(expected = "Failed to extract response.")]
@@ -279,7 +497,7 @@ mod tests {
         crate::tests::init_config_clear_map();
 
         let (_, command, lenc, response, mut lenr, mut dad, mut sad, ctn, pn) = rand_params();
-        let _ = MAP.write().insert(ctn, pn);
+        crate::tests::insert_terminal(ctn, pn);
 
         let res = data(ctn, &mut dad, &mut sad, lenc, command, &mut lenr, response);
 
@@ -301,7 +519,7 @@ mod tests {
         crate::tests::init_config_clear_map();
 
         let (_, command, lenc, response, mut lenr, mut dad, mut sad, ctn, pn) = rand_params();
-        let _ = MAP.write().insert(ctn, pn);
+        crate::tests::insert_terminal(ctn, pn);
 
         assert!(data(ctn, &mut dad, &mut sad, lenc, command, &mut lenr, response,).is_err());
 
@@ -321,7 +539,7 @@ mod tests {
         crate::tests::init_config_clear_map();
 
         let (_, command, lenc, response, mut lenr, mut dad, mut sad, ctn, pn) = rand_params();
-        let _ = MAP.write().insert(ctn, pn);
+        crate::tests::insert_terminal(ctn, pn);
 
         assert!(data(ctn, &mut dad, &mut sad, lenc, command, &mut lenr, response,).is_err());
 
@@ -347,7 +565,7 @@ mod tests {
         crate::tests::init_config_clear_map();
 
         let (_, command, lenc, response, mut lenr, mut dad, mut sad, ctn, pn) = rand_params();
-        let _ = MAP.write().insert(ctn, pn);
+        crate::tests::insert_terminal(ctn, pn);
 
         assert_eq!(
             Some(Status::ERR_MEMORY),
@@ -357,6 +575,117 @@ mod tests {
         remove_var("K2_BASE_URL");
     }
 
+    #[async_std::test]
+    #[serial]
+    async fn reopen_terminal_if_session_lost_with_default_codes() {
+        let (ctn, pn) = (rand::random::<u16>(), rand::random::<u16>());
+        let command = [0x00, 0xB0, 0x00, 0x00, 0x00];
+        let mut response = [0u8; 258];
+        let mut lenr = response.len() as u16;
+        let (mut dad, mut sad) = (0, 2);
+
+        let mock_server = MockServer::start().await;
+        Mock::given(matchers::path(format!("/ct_data/{}/{}", ctn, pn)))
+            .respond_with(ResponseTemplate::new(404))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(matchers::path(format!("/ct_init/{}/{}", ctn, pn)))
+            .respond_with(ResponseTemplate::new(200).set_body_string("0"))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(matchers::path(format!("/ct_data/{}/{}", ctn, pn)))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "dad":2,
+                "sad":1,
+                "lenr":2,
+                "response":"kAA=",
+                "responseCode":0
+            })))
+            .mount(&mock_server)
+            .await;
+        set_var("K2_BASE_URL", mock_server.uri());
+        set_var("K2_STATUS_ON_SESSION_LOST", "ERR_TRANS");
+
+        crate::tests::init_config_clear_map();
+        crate::tests::insert_terminal(ctn, pn);
+
+        assert_eq!(
+            Some(Status::ERR_TRANS),
+            data(
+                ctn,
+                &mut dad,
+                &mut sad,
+                command.len() as u16,
+                command.as_ptr(),
+                &mut lenr,
+                response.as_mut_ptr(),
+            )
+            .ok()
+        );
+        assert_eq!(
+            Some(Status::OK),
+            data(
+                ctn,
+                &mut dad,
+                &mut sad,
+                command.len() as u16,
+                command.as_ptr(),
+                &mut lenr,
+                response.as_mut_ptr(),
+            )
+            .ok()
+        );
+        assert_eq!(2, lenr);
+        assert_eq!([0x90, 0x00], response[..2]);
+
+        remove_var("K2_BASE_URL");
+        remove_var("K2_STATUS_ON_SESSION_LOST");
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn not_found_does_not_reopen_terminal_unless_listed() {
+        let (ctn, pn) = (rand::random::<u16>(), rand::random::<u16>());
+        let command = [0x00, 0xB0, 0x00, 0x00, 0x00];
+        let mut response = [0u8; 258];
+        let mut lenr = response.len() as u16;
+        let (mut dad, mut sad) = (0, 2);
+
+        let mock_server = MockServer::start().await;
+        Mock::given(matchers::path(format!("/ct_data/{}/{}", ctn, pn)))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(matchers::path_regex("^/ct_init"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("0"))
+            .expect(0)
+            .mount(&mock_server)
+            .await;
+        set_var("K2_BASE_URL", mock_server.uri());
+        set_var("K2_SESSION_LOST_STATUS_CODES", "410");
+
+        crate::tests::init_config_clear_map();
+        crate::tests::insert_terminal(ctn, pn);
+
+        assert!(data(
+            ctn,
+            &mut dad,
+            &mut sad,
+            command.len() as u16,
+            command.as_ptr(),
+            &mut lenr,
+            response.as_mut_ptr(),
+        )
+        .is_err());
+
+        remove_var("K2_BASE_URL");
+        remove_var("K2_SESSION_LOST_STATUS_CODES");
+    }
+
     #[async_std::test]
     #[serial]
     async fn use_ctn_and_pn_from_config() {
@@ -373,7 +702,7 @@ mod tests {
         set_var("K2_PN", format!("{}", pn));
         crate::tests::init_config_clear_map();
 
-        let _ = MAP.write().insert(ctn, pn);
+        crate::tests::insert_terminal(ctn, pn);
 
         let unused_ctn = rand::random::<u16>();
 
@@ -386,6 +715,176 @@ mod tests {
         remove_var("K2_PN");
     }
 
+    #[async_std::test]
+    #[serial]
+    async fn apdu_is_not_resent_after_server_error() {
+        let mock_server = MockServer::start().await;
+        Mock::given(matchers::any())
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(matchers::any())
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "dad":2,
+                "sad":1,
+                "lenr":2,
+                "response":"kAA=",
+                "responseCode":0
+            })))
+            .mount(&mock_server)
+            .await;
+        set_var("K2_BASE_URL", mock_server.uri());
+        set_var("K2_RETRY_ATTEMPTS", "5");
+        set_var("K2_RETRY_BACKOFF", "1");
+
+        crate::tests::init_config_clear_map();
+
+        let (_, command, lenc, response, mut lenr, mut dad, mut sad, ctn, pn) = rand_params();
+        crate::tests::insert_terminal(ctn, pn);
+
+        assert!(data(ctn, &mut dad, &mut sad, lenc, command, &mut lenr, response).is_err());
+        assert_eq!(
+            Some(1),
+            mock_server.received_requests().await.map(|r| r.len())
+        );
+
+        remove_var("K2_BASE_URL");
+        remove_var("K2_RETRY_ATTEMPTS");
+        remove_var("K2_RETRY_BACKOFF");
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn apdu_is_sent_to_k2_holding_the_session() {
+        let mut servers = Vec::new();
+        for _ in 0..2 {
+            let mock_server = MockServer::start().await;
+            Mock::given(matchers::any())
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                    "dad":2,
+                    "sad":1,
+                    "lenr":2,
+                    "response":"kAA=",
+                    "responseCode":0
+                })))
+                .mount(&mock_server)
+                .await;
+            servers.push(mock_server);
+        }
+        set_var(
+            "K2_BASE_URL",
+            format!("{},{}", servers[0].uri(), servers[1].uri()),
+        );
+
+        crate::tests::init_config_clear_map();
+
+        let (_, command, lenc, response, mut lenr, mut dad, mut sad, ctn, pn) = rand_params();
+        let base_url = format!("{}/", servers[1].uri());
+        crate::tests::insert_session(ctn, Terminal { pn, base_url });
+
+        assert_eq!(
+            Some(Status::OK),
+            data(ctn, &mut dad, &mut sad, lenc, command, &mut lenr, response).ok()
+        );
+        assert_eq!(
+            Some(0),
+            servers[0].received_requests().await.map(|r| r.len())
+        );
+        assert_eq!(
+            Some(1),
+            servers[1].received_requests().await.map(|r| r.len())
+        );
+
+        remove_var("K2_BASE_URL");
+    }
+
+    /// Sends an APDU on each ctn from its own thread, returning the statuses and elapsed time.
+    fn data_in_parallel(ctns: Vec<u16>) -> (Vec<Option<Status>>, Duration) {
+        let started = Instant::now();
+        let threads = ctns
+            .into_iter()
+            .map(|ctn| {
+                thread::spawn(move || {
+                    let command = [0x00, 0xB0, 0x00, 0x00, 0x00];
+                    let mut response = [0; 16];
+                    let (mut dad, mut sad, mut lenr) = (0, 2, 16);
+                    data(
+                        ctn,
+                        &mut dad,
+                        &mut sad,
+                        command.len() as u16,
+                        command.as_ptr(),
+                        &mut lenr,
+                        response.as_mut_ptr(),
+                    )
+                    .ok()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let statuses = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect();
+        (statuses, started.elapsed())
+    }
+
+    async fn delayed_server() -> MockServer {
+        let mock_server = MockServer::start().await;
+        Mock::given(matchers::any())
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({
+                        "dad":2,
+                        "sad":0,
+                        "lenr":2,
+                        "response":"kAA=",
+                        "responseCode":0
+                    }))
+                    .set_delay(Duration::from_millis(300)),
+            )
+            .mount(&mock_server)
+            .await;
+        mock_server
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn calls_on_one_terminal_are_serialized() {
+        let mock_server = delayed_server().await;
+        set_var("K2_BASE_URL", mock_server.uri());
+        crate::tests::init_config_clear_map();
+
+        let ctn = rand::random::<u16>();
+        crate::tests::insert_terminal(ctn, 1);
+
+        let (statuses, elapsed) = data_in_parallel(vec![ctn; 3]);
+        assert!(statuses.iter().all(|status| *status == Some(Status::OK)));
+        assert!(elapsed >= Duration::from_millis(900));
+
+        remove_var("K2_BASE_URL");
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn calls_on_different_terminals_run_in_parallel() {
+        let mock_server = delayed_server().await;
+        set_var("K2_BASE_URL", mock_server.uri());
+        crate::tests::init_config_clear_map();
+
+        let ctns = vec![1, 2, 3];
+        for ctn in &ctns {
+            crate::tests::insert_terminal(*ctn, *ctn);
+        }
+
+        let (statuses, elapsed) = data_in_parallel(ctns);
+        assert!(statuses.iter().all(|status| *status == Some(Status::OK)));
+        assert!(elapsed < Duration::from_millis(900));
+
+        remove_var("K2_BASE_URL");
+    }
+
     fn rand_params() -> (Vec<u8>, *const u8, u16, *mut u8, u16, u8, u8, u16, u16) {
         let mut command = vec![0; rand::random::<u16>() as usize];
         for x in command.iter_mut() {
@@ -395,7 +894,7 @@ mod tests {
         let command_ptr: *const u8 = command.as_ptr();
         let lenc: u16 = command.len() as u16;
 
-        let mut response: [u8; MAX as usize] = [rand::random::<u8>(); MAX as usize];
+        let mut response: [u8; u16::MAX as usize] = [rand::random::<u8>(); u16::MAX as usize];
         let response_ptr: *mut u8 = response.as_mut_ptr();
         let lenr: u16 = response.len() as u16;
 
@@ -1,8 +1,13 @@
-use crate::ctapi::MAP;
-use crate::{http, Status, CONFIG};
+use crate::backend;
+use crate::ctapi::{self, journal, Session, MAP};
+use crate::last_error::Code;
+use crate::{preflight, Status};
+use antidote::Mutex;
+use std::sync::Arc;
 
 pub fn init(mut ctn: u16, mut pn: u16) -> anyhow::Result<Status> {
-    if let (Some(ctn_from_cfg), Some(pn_from_cfg)) = (CONFIG.read().ctn, CONFIG.read().pn) {
+    let host_ctn = ctn;
+    if let Some((ctn_from_cfg, pn_from_cfg)) = ctapi::mapped(ctn) {
         debug!(
             "Use ctn '{}' and pn '{}' from configuration.",
             ctn_from_cfg, pn_from_cfg
@@ -11,29 +16,70 @@ pub fn init(mut ctn: u16, mut pn: u16) -> anyhow::Result<Status> {
         pn = pn_from_cfg;
     }
 
-    // Do we know this CTN?
-    if MAP.read().contains_key(&ctn) {
-        error!("Card terminal has already been opened.");
-        return Ok(Status::ERR_INVALID);
-    }
+    journal::clean_up();
+
+    let session = Arc::new(Session {
+        pn,
+        terminal: Mutex::new(None),
+    });
+    let mut guard = {
+        let mut map = MAP.write();
+
+        // Do we know this CTN?
+        if map.contains_key(&ctn) {
+            if host_ctn != ctn {
+                warn!(
+                    "Ctn {} is mapped onto ctn {}, which is already open.",
+                    host_ctn, ctn
+                );
+            }
+            reject!(Code::InvalidCall, "Card terminal has already been opened.");
+            return Ok(Status::ERR_INVALID);
+        }
+
+        // several ctns on one terminal interfere with each other
+        for (other, _) in map.iter().filter(|(_, session)| session.pn == pn) {
+            warn!(
+                "Ctn {} is opened on pn {}, which is already used by ctn {}.",
+                ctn, pn, other
+            );
+        }
 
-    let path = format!("ct_init/{}/{}", ctn, pn);
-    let response = http::request(&path, None)?;
+        // reserve the ctn, calls on it wait until the terminal is open
+        let guard = session.terminal.lock();
+        let _ = map.insert(ctn, session.clone());
+        guard
+    };
 
-    match response.parse::<i8>() {
-        Ok(status_code) => {
-            let status = Status::from(status_code);
-            if let Status::OK = status {
-                // Store CTN
-                let _ = MAP.write().insert(ctn, pn);
-                info!("Card terminal opened.");
+    if let Some(diagnosis) = preflight::before_open() {
+        let _ = MAP.write().remove(&ctn);
+        reject!(Code::IncompatibleK2, "{}", diagnosis);
+        return Ok(Status::ERR_HTSI);
+    }
+
+    let opened = backend::current().open(ctn, pn);
+    match opened {
+        Ok((status, Some(terminal))) => {
+            if let Some(diagnosis) = preflight::after_open(&terminal.base_url) {
+                // do not keep a card terminal on a K2 failed over to that we refuse to work with
+                if let Err(why) = backend::current().close(ctn, &terminal) {
+                    warn!(
+                        "Failed to close card terminal on incompatible K2: {:#}",
+                        why
+                    );
+                }
+                let _ = MAP.write().remove(&ctn);
+                reject!(Code::IncompatibleK2, "{}", diagnosis);
+                return Ok(Status::ERR_HTSI);
             }
 
+            journal::opened(ctn, &terminal);
+            *guard = Some(terminal);
             Ok(status)
         }
-        Err(why) => {
-            debug!("{}", why);
-            Err(format_err!("Unexpected server response found in body!"))
+        _ => {
+            let _ = MAP.write().remove(&ctn);
+            opened.map(|(status, _)| status)
         }
     }
 }
@@ -68,7 +114,7 @@ mod tests {
         let ctn = rand::random::<u16>();
         let pn = rand::random::<u16>();
 
-        let _ = MAP.write().insert(ctn, pn);
+        crate::tests::insert_terminal(ctn, pn);
 
         assert_eq!(Some(Status::ERR_INVALID), init(ctn, pn).ok());
     }
@@ -117,6 +163,48 @@ mod tests {
         remove_var("K2_PN");
     }
 
+    #[async_std::test]
+    #[serial]
+    async fn use_pn_from_terminal_mapping() {
+        let mock_server = MockServer::start().await;
+        Mock::given(matchers::any())
+            .respond_with(ResponseTemplate::new(200).set_body_string("0"))
+            .mount(&mock_server)
+            .await;
+        set_var("K2_BASE_URL", mock_server.uri());
+        set_var("K2_TERMINALS", "1:3,2:7");
+        set_var("K2_CTN", "9");
+        set_var("K2_PN", "12");
+        crate::tests::init_config_clear_map();
+
+        assert_eq!(Some(Status::OK), init(1, 5).ok());
+        assert_eq!(Some(Status::OK), init(2, 5).ok());
+        assert_eq!(Some(Status::OK), init(4, 5).ok());
+        assert_eq!(Some(Status::ERR_INVALID), init(5, 5).ok());
+
+        let paths = mock_server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .map(|request| request.url.path().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["/ct_init/1/3", "/ct_init/2/7", "/ct_init/9/12"], paths);
+        assert_eq!(
+            Some(3),
+            crate::tests::terminal(1).map(|terminal| terminal.pn)
+        );
+        assert_eq!(
+            Some(12),
+            crate::tests::terminal(9).map(|terminal| terminal.pn)
+        );
+
+        remove_var("K2_BASE_URL");
+        remove_var("K2_TERMINALS");
+        remove_var("K2_CTN");
+        remove_var("K2_PN");
+    }
+
     #[async_std::test]
     #[serial]
     async fn returns_err_if_server_response_is_not_200() {
@@ -199,4 +287,64 @@ mod tests {
 
         remove_var("K2_BASE_URL");
     }
+
+    #[async_std::test]
+    #[serial]
+    async fn init_is_retried_after_server_error() {
+        let mock_server = MockServer::start().await;
+        Mock::given(matchers::any())
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(matchers::any())
+            .respond_with(ResponseTemplate::new(200).set_body_json(0))
+            .mount(&mock_server)
+            .await;
+        set_var("K2_BASE_URL", mock_server.uri());
+        set_var("K2_RETRY_ATTEMPTS", "2");
+        set_var("K2_RETRY_BACKOFF", "1");
+
+        crate::tests::init_config_clear_map();
+
+        let ctn = rand::random::<u16>();
+        let pn = rand::random::<u16>();
+
+        assert_eq!(Some(Status::OK), init(ctn, pn).ok());
+        assert_eq!(
+            Some(2),
+            mock_server.received_requests().await.map(|r| r.len())
+        );
+
+        remove_var("K2_BASE_URL");
+        remove_var("K2_RETRY_ATTEMPTS");
+        remove_var("K2_RETRY_BACKOFF");
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn opens_terminal_on_next_k2_if_first_fails() {
+        let mock_server = MockServer::start().await;
+        Mock::given(matchers::any())
+            .respond_with(ResponseTemplate::new(200).set_body_json(0))
+            .mount(&mock_server)
+            .await;
+        set_var(
+            "K2_BASE_URL",
+            format!("{},{}", crate::tests::unreachable_url(), mock_server.uri()),
+        );
+
+        crate::tests::init_config_clear_map();
+
+        let ctn = rand::random::<u16>();
+        let pn = rand::random::<u16>();
+
+        assert_eq!(Some(Status::OK), init(ctn, pn).ok());
+        assert_eq!(
+            Some(format!("{}/", mock_server.uri())),
+            crate::tests::terminal(ctn).map(|terminal| terminal.base_url)
+        );
+
+        remove_var("K2_BASE_URL");
+    }
 }
@@ -0,0 +1,417 @@
+use crate::backend;
+use crate::ctapi::Terminal;
+use crate::CONFIG;
+use antidote::Mutex;
+use once_cell::sync::Lazy;
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    path::Path,
+    process,
+};
+
+/// Card terminal opened by a process, kept in `journal_path`.
+#[derive(Deserialize, Serialize)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+struct Entry {
+    pid: u32,
+    /// Start time of the process, telling it apart from a later one with the same pid.
+    #[serde(default)]
+    started: Option<u64>,
+    ctn: u16,
+    pn: u16,
+    base_url: String,
+}
+
+/// Start time of this process.
+static STARTED: Lazy<Option<u64>> = Lazy::new(|| started(process::id()));
+
+/// Serializes changes of the journal by the threads of this process.
+static LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// Exclusive lock on the file next to the journal, serializing changes by all processes.
+struct FileLock(File);
+
+impl FileLock {
+    fn acquire(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(format!("{}.lock", path))?;
+        lock(&file)?;
+        Ok(FileLock(file))
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = unlock(&self.0);
+    }
+}
+
+/// Closes the card terminals left open by processes that are gone, e.g. after a crash.
+pub fn clean_up() {
+    // take the entries out first, so no other process closes them as well
+    let mut orphaned = Vec::new();
+    update(|entries| {
+        let (dead, kept) = entries
+            .drain(..)
+            .partition(|entry| !is_own(entry) && !is_running(entry));
+        *entries = kept;
+        orphaned = dead;
+    });
+
+    let mut failed = Vec::new();
+    for entry in orphaned {
+        let terminal = Terminal {
+            pn: entry.pn,
+            base_url: entry.base_url.clone(),
+        };
+        match backend::current().close(entry.ctn, &terminal) {
+            Ok(status) => info!(
+                "Closed ctn {} on pn {} left open by process {} with status {}.",
+                entry.ctn,
+                entry.pn,
+                entry.pid,
+                i8::from(status)
+            ),
+            Err(why) => {
+                warn!(
+                    "Failed to close ctn {} left open by process {}: {:#}",
+                    entry.ctn, entry.pid, why
+                );
+                failed.push(entry);
+            }
+        }
+    }
+
+    // try again on the next CT_init
+    if !failed.is_empty() {
+        update(|entries| entries.extend(failed));
+    }
+}
+
+/// Keeps the opened card terminal in the journal.
+pub fn opened(ctn: u16, terminal: &Terminal) {
+    update(|entries| {
+        entries.retain(|entry| !(is_own(entry) && entry.ctn == ctn));
+        entries.push(Entry {
+            pid: process::id(),
+            started: *STARTED,
+            ctn,
+            pn: terminal.pn,
+            base_url: terminal.base_url.clone(),
+        });
+    });
+}
+
+/// Removes the closed card terminal from the journal.
+pub fn closed(ctn: u16) {
+    update(|entries| entries.retain(|entry| !(is_own(entry) && entry.ctn == ctn)));
+}
+
+fn is_own(entry: &Entry) -> bool {
+    entry.pid == process::id() && entry.started == *STARTED
+}
+
+/// Whether the process of the entry still runs, rather than another one given its pid since.
+fn is_running(entry: &Entry) -> bool {
+    alive(entry.pid)
+        && match (entry.started, started(entry.pid)) {
+            (Some(recorded), Some(current)) => recorded == current,
+            _ => true,
+        }
+}
+
+fn update(change: impl FnOnce(&mut Vec<Entry>)) {
+    let path = match &CONFIG.read().journal_path {
+        Some(path) => path.clone(),
+        None => return,
+    };
+
+    let _guard = LOCK.lock();
+    let _lock = match FileLock::acquire(&path) {
+        Ok(lock) => lock,
+        Err(why) => {
+            warn!("Failed to lock journal {}: {}", path, why);
+            return;
+        }
+    };
+
+    let mut entries = match read(&path) {
+        Ok(entries) => entries,
+        Err(why) => {
+            warn!("Failed to read journal {}: {}", path, why);
+            Vec::new()
+        }
+    };
+
+    change(&mut entries);
+
+    if let Err(why) = write(&path, &entries) {
+        warn!("Failed to write journal {}: {}", path, why);
+    }
+}
+
+fn read(path: &str) -> anyhow::Result<Vec<Entry>> {
+    match fs::read_to_string(path) {
+        Ok(json) if json.trim().is_empty() => Ok(Vec::new()),
+        Ok(json) => Ok(serde_json::from_str(&json)?),
+        Err(why) if why.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(why) => Err(why.into()),
+    }
+}
+
+/// Replaces the journal at once, so other processes never read it half written.
+fn write(path: &str, entries: &[Entry]) -> anyhow::Result<()> {
+    let temporary = format!("{}.{}", path, process::id());
+    fs::write(&temporary, serde_json::to_string(entries)?)?;
+    fs::rename(&temporary, Path::new(path))?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn lock(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    match unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(unix)]
+fn unlock(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    match unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_UN) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(windows)]
+fn lock(file: &File) -> io::Result<()> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::{
+        Storage::FileSystem::{LockFileEx, LOCKFILE_EXCLUSIVE_LOCK},
+        System::IO::OVERLAPPED,
+    };
+
+    let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+    let handle = file.as_raw_handle() as isize;
+    match unsafe {
+        LockFileEx(
+            handle,
+            LOCKFILE_EXCLUSIVE_LOCK,
+            0,
+            u32::MAX,
+            u32::MAX,
+            &mut overlapped,
+        )
+    } {
+        0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(windows)]
+fn unlock(file: &File) -> io::Result<()> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::{Storage::FileSystem::UnlockFileEx, System::IO::OVERLAPPED};
+
+    let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+    let handle = file.as_raw_handle() as isize;
+    match unsafe { UnlockFileEx(handle, 0, u32::MAX, u32::MAX, &mut overlapped) } {
+        0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn alive(pid: u32) -> bool {
+    // signal 0 only checks whether the process exists
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(windows)]
+fn alive(pid: u32) -> bool {
+    use windows_sys::Win32::{
+        Foundation::{CloseHandle, GetLastError, ERROR_ACCESS_DENIED, STILL_ACTIVE},
+        System::Threading::{GetExitCodeProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION},
+    };
+
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if handle == 0 {
+            return GetLastError() == ERROR_ACCESS_DENIED;
+        }
+
+        let mut code = 0;
+        let queried = GetExitCodeProcess(handle, &mut code);
+        let _ = CloseHandle(handle);
+        queried != 0 && code == STILL_ACTIVE as u32
+    }
+}
+
+/// Start time in clock ticks since boot, the 22nd field of /proc/<pid>/stat.
+#[cfg(target_os = "linux")]
+fn started(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the command in parentheses may contain spaces
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+#[cfg(target_os = "macos")]
+fn started(pid: u32) -> Option<u64> {
+    let mut info: libc::proc_bsdinfo = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of::<libc::proc_bsdinfo>() as libc::c_int;
+    let read = unsafe {
+        libc::proc_pidinfo(
+            pid as libc::c_int,
+            libc::PROC_PIDTBSDINFO,
+            0,
+            &mut info as *mut _ as *mut libc::c_void,
+            size,
+        )
+    };
+    if read != size {
+        return None;
+    }
+
+    Some(info.pbi_start_tvsec * 1_000_000 + info.pbi_start_tvusec)
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "macos"))))]
+fn started(_pid: u32) -> Option<u64> {
+    None
+}
+
+#[cfg(windows)]
+fn started(pid: u32) -> Option<u64> {
+    use windows_sys::Win32::{
+        Foundation::{CloseHandle, FILETIME},
+        System::Threading::{GetProcessTimes, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION},
+    };
+
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if handle == 0 {
+            return None;
+        }
+
+        let mut creation: FILETIME = std::mem::zeroed();
+        let (mut exit, mut kernel, mut user) = (creation, creation, creation);
+        let queried = GetProcessTimes(handle, &mut creation, &mut exit, &mut kernel, &mut user);
+        let _ = CloseHandle(handle);
+        if queried == 0 {
+            return None;
+        }
+
+        Some(u64::from(creation.dwHighDateTime) << 32 | u64::from(creation.dwLowDateTime))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{read, write, Entry, STARTED};
+    use crate::ctapi::{close::close, init::init, status::Status};
+    use std::{
+        env::{self, remove_var, set_var},
+        process::{self, Command, Stdio},
+    };
+    use tempfile::tempdir;
+    use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+    #[async_std::test]
+    #[serial]
+    async fn close_terminals_of_dead_processes() {
+        let folder = tempdir().unwrap();
+        let path = folder.path().join("journal.json");
+        let path = path.to_str().unwrap();
+
+        // the test binary only lists its tests
+        let mut child = Command::new(env::current_exe().unwrap())
+            .arg("--list")
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        let dead = child.id();
+        let _ = child.wait().unwrap();
+
+        let mock_server = MockServer::start().await;
+        Mock::given(matchers::path_regex("^/ct_close/1/11|^/ct_close/4/14"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("0"))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+        Mock::given(matchers::path_regex("^/ct_init/2/12|^/ct_close/2/12"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("0"))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+        set_var("K2_BASE_URL", mock_server.uri());
+        set_var("K2_JOURNAL_PATH", path);
+        crate::tests::init_config_clear_map();
+
+        let base_url = format!("{}/", mock_server.uri());
+        write(
+            path,
+            &[
+                Entry {
+                    pid: dead,
+                    started: None,
+                    ctn: 1,
+                    pn: 11,
+                    base_url: base_url.clone(),
+                },
+                Entry {
+                    pid: process::id(),
+                    started: *STARTED,
+                    ctn: 3,
+                    pn: 13,
+                    base_url: base_url.clone(),
+                },
+                // a process gone before this one got its pid
+                Entry {
+                    pid: process::id(),
+                    started: STARTED.map(|started| started - 1),
+                    ctn: 4,
+                    pn: 14,
+                    base_url: base_url.clone(),
+                },
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(Some(Status::OK), init(2, 12).ok());
+        assert_eq!(
+            vec![
+                Entry {
+                    pid: process::id(),
+                    started: *STARTED,
+                    ctn: 3,
+                    pn: 13,
+                    base_url: base_url.clone(),
+                },
+                Entry {
+                    pid: process::id(),
+                    started: *STARTED,
+                    ctn: 2,
+                    pn: 12,
+                    base_url: base_url.clone(),
+                }
+            ],
+            read(path).unwrap()
+        );
+
+        assert_eq!(Some(Status::OK), close(2).ok());
+        assert_eq!(1, read(path).unwrap().len());
+
+        remove_var("K2_BASE_URL");
+        remove_var("K2_JOURNAL_PATH");
+    }
+}
@@ -1,10 +1,43 @@
 pub mod close;
 pub mod data;
 pub mod init;
+pub mod journal;
 pub mod status;
 
-use antidote::RwLock;
+use crate::CONFIG;
+use antidote::{Mutex, RwLock};
 use once_cell::sync::Lazy;
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 
-pub(crate) static MAP: Lazy<RwLock<HashMap<u16, u16>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+/// Session of an opened card terminal.
+#[derive(Clone)]
+pub(crate) struct Terminal {
+    pub pn: u16,
+    /// Base URL of the K2 the terminal has been opened on, empty for the simulator.
+    pub base_url: String,
+}
+
+/// Card terminal opened for a ctn.
+pub(crate) struct Session {
+    pub pn: u16,
+    /// Locked for the whole of each call, so calls on one ctn are serialized. Empty while the
+    /// terminal is being opened and after it has been closed.
+    pub terminal: Mutex<Option<Terminal>>,
+}
+
+pub(crate) static MAP: Lazy<RwLock<HashMap<u16, Arc<Session>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Session of the ctn, without holding the lock on `MAP` any longer.
+pub(crate) fn session(ctn: u16) -> Option<Arc<Session>> {
+    MAP.read().get(&ctn).cloned()
+}
+
+/// Card terminal a ctn of the host is mapped to, by `terminals` or else the global `ctn` and `pn`.
+pub(crate) fn mapped(ctn: u16) -> Option<(u16, u16)> {
+    let config = CONFIG.read();
+    match config.terminals.iter().find(|terminal| terminal.ctn == ctn) {
+        Some(terminal) => Some((terminal.ctn, terminal.pn)),
+        None => config.ctn.zip(config.pn),
+    }
+}
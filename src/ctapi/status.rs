@@ -1,5 +1,6 @@
 #[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 #[cfg_attr(test, derive(Debug, PartialEq))]
+#[derive(Clone, Copy, Deserialize)]
 #[repr(i8)]
 pub enum Status {
     OK = 0,
@@ -0,0 +1,88 @@
+use crate::ctapi::{self, status::Status};
+use crate::last_error::{self, Code};
+use crate::{logging, settings};
+use std::panic::{self, UnwindSafe};
+
+/// Runs a CT-API function for the host. Panics, including those while reading the configuration
+/// or initializing logging, become ERR_HTSI instead of unwinding into the host, and so does an
+/// invalid configuration.
+pub fn guard(function: &str, ctn: u16, call: impl FnOnce() -> i8 + UnwindSafe) -> i8 {
+    last_error::begin();
+
+    let status = panic::catch_unwind(|| {
+        logging::init();
+
+        if let Some(why) = settings::invalid() {
+            reject!(Code::InvalidConfiguration, "Invalid configuration: {}", why);
+            return Status::ERR_HTSI.into();
+        }
+
+        call()
+    })
+    .unwrap_or_else(|why| {
+        reject!(Code::Internal, "Caught panic during {}!", function);
+        debug!("{:#?}", why);
+        Status::ERR_HTSI.into()
+    });
+
+    last_error::finish(ctn, function, status);
+    debug!("Returning {}", status);
+    status
+}
+
+/// Runs a diagnostic function for the host, returning the fallback on a panic.
+pub fn catch<T>(fallback: T, call: impl FnOnce() -> T + UnwindSafe) -> T {
+    panic::catch_unwind(call).unwrap_or(fallback)
+}
+
+/// Runs when the host exits or unloads the library, closing card terminals it left open so K2
+/// does not keep them blocked for the next process.
+#[ctor::dtor]
+fn unload() {
+    catch((), || {
+        let _ = ctapi::close::close_all();
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::guard;
+    use crate::{
+        ctapi::status::Status,
+        last_error::{self, Code},
+        settings, CONFIG,
+    };
+    use std::env::{remove_var, set_var};
+
+    #[test]
+    #[serial]
+    fn panic_becomes_err_htsi() {
+        crate::tests::init_config_clear_map();
+
+        assert_eq!(
+            i8::from(Status::ERR_HTSI),
+            guard("CT_test", 1, || panic!("boom"))
+        );
+        assert_eq!(Code::Internal, last_error::code());
+    }
+
+    #[test]
+    #[serial]
+    fn invalid_configuration_is_rejected() {
+        set_var("K2_RETRY_ATTEMPTS", "0");
+        *CONFIG.write() = settings::load();
+
+        assert_eq!(i8::from(Status::ERR_HTSI), guard("CT_test", 1, || 0));
+        assert_eq!(Code::InvalidConfiguration, last_error::code());
+        assert!(last_error::message(1)
+            .unwrap()
+            .contains("retry_attempts has to be at least 1"));
+
+        remove_var("K2_RETRY_ATTEMPTS");
+        crate::tests::init_config_clear_map();
+
+        assert_eq!(0, guard("CT_test", 1, || 0));
+        assert_eq!(Code::None, last_error::code());
+    }
+}
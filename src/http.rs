@@ -1,38 +1,696 @@
-use crate::CONFIG;
+use crate::ctapi::status::Status;
+use crate::settings::Settings;
+use crate::{tls, AGENTS, CONFIG};
+use antidote::Mutex;
+use data_encoding::BASE64;
+use once_cell::sync::Lazy;
+use rand::Rng;
 use serde_json::Value;
-use std::time::Duration;
+use std::{
+    collections::HashMap,
+    env, error, fmt, io, thread,
+    time::{Duration, Instant},
+};
+use url::Url;
 
-pub fn request(path: &str, request_body: Option<Value>) -> anyhow::Result<String> {
-    let builder = ureq::builder();
-    let agent = match CONFIG.read().timeout {
-        None => builder.build(),
-        Some(timeout) => builder.timeout(Duration::from_secs(timeout)).build(),
+mod auth;
+#[cfg(unix)]
+mod unix;
+
+#[derive(Debug)]
+pub enum Error {
+    /// K2 could not be reached, e.g., connection refused or unknown host.
+    Unreachable(String),
+    /// K2 did not answer within the configured timeout.
+    Timeout,
+    /// The TLS connection to K2 could not be established.
+    Tls(String),
+    /// K2 answered with a non-success HTTP status code, along with the start of its body.
+    Status(u16, String),
+    /// K2 answered with a body that could not be understood.
+    Malformed(String),
+}
+
+impl Error {
+    pub fn status(&self) -> Status {
+        let config = CONFIG.read();
+        match self {
+            Error::Unreachable(_) | Error::Tls(_) => config.status_on_unreachable,
+            Error::Timeout => config.status_on_timeout,
+            Error::Status(..) => config.status_on_http_error,
+            Error::Malformed(_) => config.status_on_malformed,
+        }
+    }
+
+    fn is_transient(&self) -> bool {
+        match self {
+            Error::Unreachable(_) | Error::Timeout => true,
+            Error::Status(code, _) => *code >= 500,
+            Error::Tls(_) | Error::Malformed(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Unreachable(why) => write!(f, "K2 is unreachable: {}", why),
+            Error::Timeout => write!(f, "Request to K2 timed out"),
+            Error::Tls(why) => write!(f, "TLS handshake with K2 failed: {}", why),
+            Error::Status(code, body) if body.is_empty() => {
+                write!(f, "Request failed with status code {}", code)
+            }
+            Error::Status(code, body) => {
+                write!(f, "Request failed with status code {}: {}", code, body)
+            }
+            Error::Malformed(why) => write!(f, "{}", why),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<ureq::Error> for Error {
+    fn from(why: ureq::Error) -> Self {
+        match why {
+            ureq::Error::Status(code, response) => {
+                debug!("{:?}", response);
+                Error::Status(code, excerpt(&response.into_string().unwrap_or_default()))
+            }
+            ureq::Error::Transport(transport) => {
+                debug!("{:?}", transport);
+                if is_timeout(&transport) {
+                    return Error::Timeout;
+                }
+
+                if let Some(why) = tls_error(&transport) {
+                    return Error::Tls(why);
+                }
+
+                match transport.kind() {
+                    ureq::ErrorKind::BadStatus | ureq::ErrorKind::BadHeader => {
+                        Error::Malformed(transport.to_string())
+                    }
+                    _ => Error::Unreachable(transport.to_string()),
+                }
+            }
+        }
+    }
+}
+
+/// Start of an error body, short enough for logs and diagnostics.
+fn excerpt(body: &str) -> String {
+    let body = body.trim();
+    match body.char_indices().nth(200) {
+        Some((end, _)) => format!("{}...", &body[..end]),
+        None => body.to_string(),
+    }
+}
+
+fn is_timeout(transport: &ureq::Transport) -> bool {
+    let mut source = error::Error::source(transport);
+    while let Some(why) = source {
+        if let Some(io_error) = why.downcast_ref::<io::Error>() {
+            if let io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock = io_error.kind() {
+                return true;
+            }
+        }
+        source = why.source();
+    }
+
+    false
+}
+
+fn tls_error(transport: &ureq::Transport) -> Option<String> {
+    let mut source = error::Error::source(transport);
+    while let Some(why) = source {
+        if let Some(tls_error) = why.downcast_ref::<rustls::Error>() {
+            return Some(tls_error.to_string());
+        }
+
+        if let Some(inner) = why
+            .downcast_ref::<io::Error>()
+            .and_then(|io_error| io_error.get_ref())
+            .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+        {
+            return Some(inner.to_string());
+        }
+        source = why.source();
+    }
+
+    None
+}
+
+/// Settings the http agent is built from.
+#[derive(Clone, PartialEq)]
+struct AgentSettings {
+    connect_timeout: Option<u64>,
+    read_timeout: Option<u64>,
+    pool_size: usize,
+    pool_idle_timeout: u64,
+    client_cert: Option<String>,
+    client_key: Option<String>,
+    client_pkcs12: Option<String>,
+    client_pkcs12_password: Option<String>,
+    ca_file: Option<String>,
+    builtin_roots: bool,
+    pinned_public_keys: Vec<String>,
+    proxy: Option<String>,
+}
+
+impl From<&Settings> for AgentSettings {
+    fn from(config: &Settings) -> Self {
+        AgentSettings {
+            connect_timeout: config.connect_timeout,
+            read_timeout: config.read_timeout,
+            pool_size: config.pool_size,
+            pool_idle_timeout: config.pool_idle_timeout,
+            client_cert: config.client_cert.clone(),
+            client_key: config.client_key.clone(),
+            client_pkcs12: config.client_pkcs12.clone(),
+            client_pkcs12_password: config.client_pkcs12_password.clone(),
+            ca_file: config.ca_file.clone(),
+            builtin_roots: config.builtin_roots,
+            pinned_public_keys: config.pinned_public_keys.clone(),
+            proxy: None,
+        }
+    }
+}
+
+/// Process-wide http agent keeping idle connections to K2 alive.
+pub struct PooledAgent {
+    agent: ureq::Agent,
+    settings: AgentSettings,
+    last_used: Instant,
+}
+
+impl PooledAgent {
+    fn is_reusable(&self, settings: &AgentSettings) -> bool {
+        self.settings == *settings
+            && (settings.pool_idle_timeout == 0
+                || self.last_used.elapsed() < Duration::from_millis(settings.pool_idle_timeout))
+    }
+}
+
+fn agent(base_url: &str) -> Result<ureq::Agent, Error> {
+    let config = CONFIG.read();
+    let mut settings = AgentSettings::from(&*config);
+    settings.proxy = proxy_for(&config, base_url);
+
+    let mut agents = AGENTS.lock();
+    if let Some(pooled) = agents
+        .get_mut(&settings.proxy)
+        .filter(|pooled| pooled.is_reusable(&settings))
+    {
+        pooled.last_used = Instant::now();
+        return Ok(pooled.agent.clone());
+    }
+
+    debug!("Building new http agent.");
+    let tls_config = tls::client_config(&config).map_err(|why| Error::Tls(why.to_string()))?;
+    let mut builder = ureq::builder()
+        .tls_config(tls_config)
+        .max_idle_connections(settings.pool_size)
+        .max_idle_connections_per_host(settings.pool_size);
+    if let Some(proxy) = &settings.proxy {
+        builder = builder
+            .proxy(ureq::Proxy::new(proxy).map_err(|why| Error::Unreachable(why.to_string()))?);
+
+        if let Some(credentials) = proxy_credentials(proxy) {
+            builder = builder.middleware(ProxyAuthorization(format!(
+                "Basic {}",
+                BASE64.encode(credentials.as_bytes())
+            )));
+        }
+    }
+    if let Some(timeout) = settings.connect_timeout.filter(|timeout| *timeout > 0) {
+        builder = builder.timeout_connect(Duration::from_millis(timeout));
+    }
+    if let Some(timeout) = settings.read_timeout.filter(|timeout| *timeout > 0) {
+        builder = builder.timeout_read(Duration::from_millis(timeout));
+    }
+    let agent = builder.build();
+
+    let _ = agents.insert(
+        settings.proxy.clone(),
+        PooledAgent {
+            agent: agent.clone(),
+            settings,
+            last_used: Instant::now(),
+        },
+    );
+
+    Ok(agent)
+}
+
+/// Authenticates plain http requests at the proxy, ureq does so only when tunneling https.
+struct ProxyAuthorization(String);
+
+impl ureq::Middleware for ProxyAuthorization {
+    fn handle(
+        &self,
+        request: ureq::Request,
+        next: ureq::MiddlewareNext<'_>,
+    ) -> Result<ureq::Response, ureq::Error> {
+        if request.url().starts_with("http:") {
+            next.handle(request.set("Proxy-Authorization", &self.0))
+        } else {
+            next.handle(request)
+        }
+    }
+}
+
+/// Proxy to send requests to the given K2 through, including credentials.
+fn proxy_for(config: &Settings, base_url: &str) -> Option<String> {
+    let url = Url::parse(base_url).ok()?;
+    let host = url
+        .host_str()?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+
+    let mut no_proxy = config.no_proxy.clone();
+    if config.proxy_from_env {
+        if let Some(hosts) = env_var(&["NO_PROXY", "no_proxy"]) {
+            no_proxy.extend(crate::settings::split_list(&hosts));
+        }
+    }
+
+    if no_proxy.iter().any(|pattern| is_excluded(host, pattern)) {
+        return None;
+    }
+
+    let proxy = match &config.proxy {
+        Some(proxy) => proxy.clone(),
+        None if config.proxy_from_env && url.scheme() == "https" => {
+            env_var(&["HTTPS_PROXY", "https_proxy"])?
+        }
+        None if config.proxy_from_env => env_var(&["HTTP_PROXY", "http_proxy"])?,
+        None => return None,
     };
 
-    let url = format!("{}{}", CONFIG.read().base_url, path);
-    debug!("Request URL: {}", url);
-    let request = agent.post(&url).set("Content-Type", "application/json");
+    Some(match &config.proxy_auth {
+        Some(auth) if !proxy.contains('@') => match proxy.split_once("://") {
+            Some((scheme, address)) => format!("{}://{}@{}", scheme, auth, address),
+            None => format!("{}@{}", auth, proxy),
+        },
+        _ => proxy,
+    })
+}
+
+fn proxy_credentials(proxy: &str) -> Option<String> {
+    let url = if proxy.contains("://") {
+        Url::parse(proxy)
+    } else {
+        Url::parse(&format!("http://{}", proxy))
+    }
+    .ok()?;
 
-    let response = match request_body {
-        Some(json) => {
-            debug!("Request body: {:?}", json);
-            request.send_json(json)
+    match (url.username(), url.password()) {
+        ("", None) => None,
+        (user, password) => Some(format!("{}:{}", user, password.unwrap_or(""))),
+    }
+}
+
+fn env_var(names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .filter_map(|name| env::var(name).ok())
+        .find(|value| !value.trim().is_empty())
+}
+
+/// Matches a host against a no_proxy entry like `*`, `example.com` or `.example.com`.
+fn is_excluded(host: &str, pattern: &str) -> bool {
+    let pattern = pattern
+        .trim()
+        .trim_start_matches("*.")
+        .trim_start_matches('.');
+    let host = host.to_ascii_lowercase();
+    let pattern = pattern.to_ascii_lowercase();
+
+    pattern == "*" || host == pattern || host.ends_with(&format!(".{}", pattern))
+}
+
+/// CT-API function a request to K2 is sent for.
+#[derive(Clone, Copy)]
+pub enum Operation {
+    Init,
+    Data,
+    Close,
+    /// Close on `K2_shutdown`, in a single attempt ending at the deadline.
+    Shutdown(Instant),
+    /// Query about K2 itself, e.g. by `preflight`, sent once as GET.
+    Info,
+}
+
+impl Operation {
+    fn retry(self) -> Retry {
+        match self {
+            Operation::Init | Operation::Close | Operation::Shutdown(_) | Operation::Info => {
+                Retry::Idempotent
+            }
+            Operation::Data => Retry::UnlessSent,
+        }
+    }
+
+    fn attempts(self, config: &Settings) -> u32 {
+        match self {
+            Operation::Shutdown(_) | Operation::Info => 1,
+            _ => config.retry_attempts,
         }
-        _ => {
-            debug!("Empty request body...");
-            request.call()
+    }
+
+    fn method(self) -> &'static str {
+        match self {
+            Operation::Info => "GET",
+            _ => "POST",
         }
+    }
+
+    /// Time a single attempt may take in total, `None` if unlimited.
+    fn timeout(self, config: &Settings) -> Option<Duration> {
+        let timeout = match self {
+            Operation::Init | Operation::Info => config.init_timeout,
+            Operation::Data => config.data_timeout,
+            Operation::Close => config.close_timeout,
+            Operation::Shutdown(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                return Some(remaining.max(Duration::from_millis(1)));
+            }
+        };
+
+        timeout
+            .or_else(|| config.timeout.map(|seconds| seconds.saturating_mul(1000)))
+            .filter(|timeout| *timeout > 0)
+            .map(Duration::from_millis)
+    }
+}
+
+/// Determines which failed requests may be sent to K2 again.
+#[derive(Clone, Copy)]
+enum Retry {
+    /// Request can be repeated without side effects.
+    Idempotent,
+    /// Request may only be repeated if it never reached K2, e.g., an APDU.
+    UnlessSent,
+}
+
+struct Failure {
+    error: Error,
+    /// Whether the request may have reached K2.
+    sent: bool,
+}
+
+struct RetryPolicy {
+    attempts: u32,
+    backoff: u64,
+    max_backoff: u64,
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with jitter, taking a random delay from the upper half.
+    fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        let delay = self
+            .backoff
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff);
+        let jitter = rand::thread_rng().gen_range(0..=delay / 2);
+
+        Duration::from_millis(delay - jitter)
+    }
+}
+
+/// Health of the configured K2 instances.
+#[derive(Default)]
+struct Health {
+    /// Base URL new sessions are opened on as long as it works.
+    active: Option<String>,
+    /// Base URLs which failed recently, with time of failure.
+    failed: HashMap<String, Instant>,
+}
+
+impl Health {
+    /// Orders base URLs starting at the active one, recently failed ones last.
+    fn candidates(&self, base_urls: &[String], cooldown: Duration) -> Vec<String> {
+        let start = self
+            .active
+            .as_ref()
+            .and_then(|active| base_urls.iter().position(|url| url == active))
+            .unwrap_or(0);
+        let (healthy, failed): (Vec<String>, Vec<String>) = base_urls
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(base_urls.len())
+            .cloned()
+            .partition(|url| {
+                self.failed
+                    .get(url)
+                    .is_none_or(|failed_at| failed_at.elapsed() >= cooldown)
+            });
+
+        healthy.into_iter().chain(failed).collect()
+    }
+}
+
+static HEALTH: Lazy<Mutex<Health>> = Lazy::new(|| Mutex::new(Health::default()));
+
+/// Base URL of the K2 the next session would be opened on.
+pub fn active() -> Option<String> {
+    let (base_urls, cooldown) = {
+        let config = CONFIG.read();
+        (
+            config.base_url.clone(),
+            Duration::from_millis(config.failover_cooldown),
+        )
     };
 
-    match response {
-        Ok(res) => res.into_string().map_err(anyhow::Error::from),
-        Err(ureq::Error::Status(code, response)) => {
-            debug!("{:?}", response);
-            Err(format_err!("Request failed with status code {}", code))
+    HEALTH
+        .lock()
+        .candidates(&base_urls, cooldown)
+        .into_iter()
+        .next()
+}
+
+#[cfg(test)]
+pub fn reset() {
+    *HEALTH.lock() = Health::default();
+    auth::reset();
+}
+
+fn is_retryable(retry: Retry, failure: &Failure) -> bool {
+    match retry {
+        Retry::Idempotent => failure.error.is_transient(),
+        Retry::UnlessSent => !failure.sent && failure.error.is_transient(),
+    }
+}
+
+/// Sends a request to the active K2 and fails over to the other configured instances, unless it
+/// may have reached one, which might then keep a session nobody knows of.
+/// Returns the base URL of the answering K2 together with its response.
+pub fn request(
+    path: &str,
+    request_body: Option<Value>,
+    operation: Operation,
+) -> Result<(String, String), Error> {
+    let (base_urls, cooldown) = {
+        let config = CONFIG.read();
+        (
+            config.base_url.clone(),
+            Duration::from_millis(config.failover_cooldown),
+        )
+    };
+
+    let candidates = HEALTH.lock().candidates(&base_urls, cooldown);
+    let mut last_failure = None;
+    for base_url in candidates {
+        match request_with_retry(&base_url, path, request_body.clone(), operation) {
+            Ok(response) => {
+                let mut health = HEALTH.lock();
+                if health.active.as_ref() != Some(&base_url) {
+                    info!("Using K2 at {}", base_url);
+                    health.active = Some(base_url.clone());
+                }
+
+                return Ok((base_url, response));
+            }
+            Err(failure) if is_retryable(Retry::UnlessSent, &failure) => {
+                warn!("K2 at {} failed: {}", base_url, failure.error);
+                last_failure = Some(failure);
+            }
+            Err(failure) => return Err(failure.error),
+        }
+    }
+
+    Err(last_failure
+        .map(|failure| failure.error)
+        .unwrap_or_else(|| Error::Unreachable(String::from("No K2 configured"))))
+}
+
+/// Sends a request to the given K2 only, e.g., the one holding a terminal session.
+pub fn request_to(
+    base_url: &str,
+    path: &str,
+    request_body: Option<Value>,
+    operation: Operation,
+) -> Result<String, Error> {
+    request_with_retry(base_url, path, request_body, operation).map_err(|failure| failure.error)
+}
+
+fn request_with_retry(
+    base_url: &str,
+    path: &str,
+    request_body: Option<Value>,
+    operation: Operation,
+) -> Result<String, Failure> {
+    let (policy, timeout) = {
+        let config = CONFIG.read();
+        let policy = RetryPolicy {
+            attempts: operation.attempts(&config),
+            backoff: config.retry_backoff,
+            max_backoff: config.retry_max_backoff,
+        };
+
+        (policy, operation.timeout(&config))
+    };
+
+    let mut attempt = 1;
+    loop {
+        let failure = match send(
+            operation.method(),
+            base_url,
+            path,
+            request_body.clone(),
+            timeout,
+        ) {
+            Ok(response) => {
+                let _ = HEALTH.lock().failed.remove(base_url);
+                return Ok(response);
+            }
+            Err(failure) => failure,
+        };
+
+        if failure.error.is_transient() {
+            let _ = HEALTH
+                .lock()
+                .failed
+                .insert(base_url.to_string(), Instant::now());
+        }
+
+        if !is_retryable(operation.retry(), &failure) || attempt >= policy.attempts {
+            return Err(failure);
+        }
+
+        let delay = policy.delay(attempt);
+        warn!(
+            "Request failed ({}), retrying in {} ms ({}/{})",
+            failure.error,
+            delay.as_millis(),
+            attempt,
+            policy.attempts - 1
+        );
+        thread::sleep(delay);
+        attempt += 1;
+    }
+}
+
+fn send(
+    method: &str,
+    base_url: &str,
+    path: &str,
+    request_body: Option<Value>,
+    timeout: Option<Duration>,
+) -> Result<String, Failure> {
+    debug!("Request URL: {}{}", base_url, path);
+    match &request_body {
+        // the body carries the APDU, which is only logged redacted by the caller
+        Some(json) => debug!("Request body with {} bytes", json.to_string().len()),
+        None => debug!("Empty request body..."),
+    }
+
+    let mut unauthorized = false;
+    loop {
+        let authorization =
+            auth::authorization(timeout).map_err(|error| Failure { error, sent: false })?;
+        let response = transmit(
+            method,
+            base_url,
+            path,
+            request_body.as_ref(),
+            timeout,
+            authorization.as_deref(),
+        );
+
+        // K2 rejected the token, so retry once with a fresh one
+        match response {
+            Err(Failure {
+                error: Error::Status(401, _),
+                ..
+            }) if !unauthorized && auth::invalidate() => {
+                warn!("Access token was rejected, requesting a new one");
+                unauthorized = true;
+            }
+            response => return response,
+        }
+    }
+}
+
+fn transmit(
+    method: &str,
+    base_url: &str,
+    path: &str,
+    request_body: Option<&Value>,
+    timeout: Option<Duration>,
+    authorization: Option<&str>,
+) -> Result<String, Failure> {
+    #[cfg(unix)]
+    {
+        if let Some(socket) = unix::socket_path(base_url) {
+            return unix::send(method, &socket, path, request_body, timeout, authorization);
         }
+    }
+
+    let agent = agent(base_url).map_err(|error| Failure { error, sent: false })?;
+    let mut request = agent
+        .request(method, &format!("{}{}", base_url, path))
+        .set("Content-Type", "application/json");
+    if let Some(timeout) = timeout {
+        request = request.timeout(timeout);
+    }
+    if let Some(authorization) = authorization {
+        request = request.set("Authorization", authorization);
+    }
+
+    let response = match request_body {
+        Some(json) => request.send_json(json),
+        None => request.call(),
+    };
+
+    match response {
+        Ok(res) => res.into_string().map_err(|why| Failure {
+            error: if let io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock = why.kind() {
+                Error::Timeout
+            } else {
+                Error::Malformed(why.to_string())
+            },
+            sent: true,
+        }),
         Err(why) => {
-            debug!("{:?}", why);
-            Err(format_err!("Request failed with unknown error",))
+            let sent = match &why {
+                ureq::Error::Transport(transport) => {
+                    tls_error(transport).is_some()
+                        || !matches!(
+                            transport.kind(),
+                            ureq::ErrorKind::Dns | ureq::ErrorKind::ConnectionFailed
+                        )
+                }
+                ureq::Error::Status(..) => true,
+            };
+
+            Err(Failure {
+                error: Error::from(why),
+                sent,
+            })
         }
     }
 }
@@ -40,11 +698,29 @@ pub fn request(path: &str, request_body: Option<Value>) -> anyhow::Result<String
 #[cfg(test)]
 mod tests {
 
-    use super::request;
-    use crate::{tests::random_string, Settings, CONFIG};
-    use std::{env, time::Duration};
+    use super::{
+        is_excluded, is_retryable, request, request_to, Error, Failure, Operation, Retry,
+        RetryPolicy, HEALTH,
+    };
+    use crate::{
+        ctapi::status::Status,
+        tests::{http_server, random_string, read_http_request, tls_server, unreachable_url, Pki},
+        Settings, CONFIG,
+    };
+    use data_encoding::BASE64;
+    use p12_keystore::{Certificate, KeyStore, KeyStoreEntry, PrivateKeyChain};
+    use ring::digest::{digest, SHA256};
+    use std::{
+        env, fs,
+        io::Write,
+        net::TcpListener,
+        sync::atomic::Ordering,
+        thread,
+        time::{Duration, Instant},
+    };
+    use tempfile::tempdir;
     use wiremock::{
-        matchers::{body_json, body_string, header},
+        matchers::{any, body_json, body_string, header},
         Mock, MockServer, ResponseTemplate,
     };
 
@@ -61,7 +737,11 @@ mod tests {
 
         init_config();
 
-        let _ = request("", Some(json!({ "body": random_string(100) })));
+        let _ = request(
+            "",
+            Some(json!({ "body": random_string(100) })),
+            Operation::Init,
+        );
 
         env::remove_var("K2_BASE_URL");
     }
@@ -95,7 +775,7 @@ mod tests {
 
         env::set_var("K2_BASE_URL", mock_server.uri());
 
-        let _ = request("", None);
+        let _ = request("", None, Operation::Init);
 
         env::remove_var("K2_BASE_URL");
     }
@@ -113,12 +793,12 @@ mod tests {
         env::set_var("K2_TIMEOUT", "6");
         init_config();
 
-        request("", None).ok();
+        request("", None, Operation::Init).ok();
 
         env::set_var("K2_TIMEOUT", "1");
         init_config();
 
-        let res = request("", None).err();
+        let res = request("", None, Operation::Init).err();
         assert_eq!(
             format!("{}", res.unwrap()),
             "Request failed with status code 404"
@@ -128,9 +808,895 @@ mod tests {
         env::remove_var("K2_TIMEOUT");
     }
 
-    fn init_config() {
-        let mut config_guard = CONFIG.write();
-        *config_guard = Settings::init().unwrap();
-        drop(config_guard);
+    #[async_std::test]
+    #[serial]
+    async fn per_operation_timeout_overrides_timeout() {
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(1500)))
+            .mount(&mock_server)
+            .await;
+
+        env::set_var("K2_BASE_URL", mock_server.uri());
+        env::set_var("K2_TIMEOUT", "1");
+        env::set_var("K2_DATA_TIMEOUT", "3000");
+        env::set_var("K2_CLOSE_TIMEOUT", "0");
+        init_config();
+
+        assert!(matches!(
+            request("", None, Operation::Init),
+            Err(Error::Timeout)
+        ));
+        assert!(request("", None, Operation::Data).is_ok());
+        assert!(request("", None, Operation::Close).is_ok());
+
+        env::set_var("K2_INIT_TIMEOUT", "500");
+        env::set_var("K2_TIMEOUT", "0");
+        init_config();
+
+        assert!(matches!(
+            request("", None, Operation::Init),
+            Err(Error::Timeout)
+        ));
+
+        env::remove_var("K2_BASE_URL");
+        env::remove_var("K2_TIMEOUT");
+        env::remove_var("K2_INIT_TIMEOUT");
+        env::remove_var("K2_DATA_TIMEOUT");
+        env::remove_var("K2_CLOSE_TIMEOUT");
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn read_timeout_in_milliseconds() {
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(500)))
+            .mount(&mock_server)
+            .await;
+
+        env::set_var("K2_BASE_URL", mock_server.uri());
+        env::set_var("K2_READ_TIMEOUT", "100");
+        init_config();
+
+        assert!(matches!(
+            request("", None, Operation::Init),
+            Err(Error::Timeout)
+        ));
+
+        env::set_var("K2_READ_TIMEOUT", "0");
+        init_config();
+
+        assert!(request("", None, Operation::Init).is_ok());
+
+        env::remove_var("K2_BASE_URL");
+        env::remove_var("K2_READ_TIMEOUT");
+    }
+
+    #[test]
+    #[serial]
+    fn unreachable_server_is_classified() {
+        env::set_var("K2_BASE_URL", "http://127.0.0.1:65432");
+        init_config();
+
+        match request("", None, Operation::Init) {
+            Err(why @ Error::Unreachable(_)) => assert_eq!(Status::ERR_HTSI, why.status()),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        env::remove_var("K2_BASE_URL");
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn timeout_is_classified() {
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(3)))
+            .mount(&mock_server)
+            .await;
+
+        env::set_var("K2_BASE_URL", mock_server.uri());
+        env::set_var("K2_TIMEOUT", "1");
+        init_config();
+
+        match request("", None, Operation::Init) {
+            Err(why @ Error::Timeout) => assert_eq!(Status::ERR_HTSI, why.status()),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        env::remove_var("K2_BASE_URL");
+        env::remove_var("K2_TIMEOUT");
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn http_error_status_is_classified() {
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&mock_server)
+            .await;
+
+        env::set_var("K2_BASE_URL", mock_server.uri());
+        init_config();
+
+        match request("", None, Operation::Init) {
+            Err(why @ Error::Status(503, _)) => assert_eq!(Status::ERR_HTSI, why.status()),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        env::remove_var("K2_BASE_URL");
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn status_mapping_is_configurable() {
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&mock_server)
+            .await;
+
+        env::set_var("K2_BASE_URL", mock_server.uri());
+        env::set_var("K2_STATUS_ON_HTTP_ERROR", "ERR_HOST");
+        init_config();
+
+        assert_eq!(
+            Some(Status::ERR_HOST),
+            request("", None, Operation::Init)
+                .err()
+                .map(|why| why.status())
+        );
+
+        env::remove_var("K2_BASE_URL");
+        env::remove_var("K2_STATUS_ON_HTTP_ERROR");
+    }
+
+    #[test]
+    #[serial]
+    fn mutual_tls_with_pem_files() {
+        let pki = Pki::new();
+        let port = tls_server(&pki, &pki.issue("localhost"), true, "0");
+        let client = pki.issue("client");
+
+        let folder = tempdir().unwrap();
+        let cert = folder.path().join("client.crt");
+        let key = folder.path().join("client.key");
+        fs::write(&cert, client.cert.pem()).unwrap();
+        fs::write(&key, client.key.serialize_pem()).unwrap();
+
+        let ca_file = pki.ca_file();
+        env::set_var("K2_CA_FILE", ca_file.path());
+        env::set_var("K2_BASE_URL", format!("https://localhost:{}", port));
+        env::set_var("K2_CLIENT_CERT", cert.to_str().unwrap());
+        env::set_var("K2_CLIENT_KEY", key.to_str().unwrap());
+        init_config();
+
+        assert_eq!(
+            Some(String::from("0")),
+            request("ct_init/1/1", None, Operation::Init)
+                .ok()
+                .map(|(_, body)| body)
+        );
+
+        env::remove_var("K2_CA_FILE");
+        env::remove_var("K2_BASE_URL");
+        env::remove_var("K2_CLIENT_CERT");
+        env::remove_var("K2_CLIENT_KEY");
+    }
+
+    #[test]
+    #[serial]
+    fn mutual_tls_with_pkcs12_file() {
+        let pki = Pki::new();
+        let port = tls_server(&pki, &pki.issue("localhost"), true, "0");
+        let client = pki.issue("client");
+
+        let mut keystore = KeyStore::new();
+        keystore.add_entry(
+            "client",
+            KeyStoreEntry::PrivateKeyChain(PrivateKeyChain::new(
+                client.key.serialize_der(),
+                [1],
+                vec![Certificate::from_der(client.cert.der()).unwrap()],
+            )),
+        );
+        let folder = tempdir().unwrap();
+        let pkcs12 = folder.path().join("client.p12");
+        fs::write(&pkcs12, keystore.writer("secret").write().unwrap()).unwrap();
+
+        let ca_file = pki.ca_file();
+        env::set_var("K2_CA_FILE", ca_file.path());
+        env::set_var("K2_BASE_URL", format!("https://localhost:{}", port));
+        env::set_var("K2_CLIENT_PKCS12", pkcs12.to_str().unwrap());
+        env::set_var("K2_CLIENT_PKCS12_PASSWORD", "secret");
+        init_config();
+
+        assert_eq!(
+            Some(String::from("0")),
+            request("ct_init/1/1", None, Operation::Init)
+                .ok()
+                .map(|(_, body)| body)
+        );
+
+        env::set_var("K2_CLIENT_PKCS12_PASSWORD", "wrong");
+        init_config();
+
+        assert!(matches!(
+            request("ct_init/1/1", None, Operation::Init),
+            Err(Error::Tls(_))
+        ));
+
+        env::remove_var("K2_CA_FILE");
+        env::remove_var("K2_BASE_URL");
+        env::remove_var("K2_CLIENT_PKCS12");
+        env::remove_var("K2_CLIENT_PKCS12_PASSWORD");
+    }
+
+    #[test]
+    #[serial]
+    fn handshake_failure_without_client_certificate() {
+        let pki = Pki::new();
+        let port = tls_server(&pki, &pki.issue("localhost"), true, "0");
+
+        let ca_file = pki.ca_file();
+        env::set_var("K2_CA_FILE", ca_file.path());
+        env::set_var("K2_BASE_URL", format!("https://localhost:{}", port));
+        init_config();
+
+        match request("ct_init/1/1", None, Operation::Init) {
+            Err(why @ Error::Tls(_)) => assert_eq!(Status::ERR_HTSI, why.status()),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        env::remove_var("K2_CA_FILE");
+        env::remove_var("K2_BASE_URL");
+    }
+
+    #[test]
+    #[serial]
+    fn untrusted_server_certificate_fails() {
+        let pki = Pki::new();
+        let port = tls_server(&pki, &pki.issue("localhost"), false, "0");
+
+        env::set_var("K2_BASE_URL", format!("https://localhost:{}", port));
+        init_config();
+
+        assert!(matches!(
+            request("ct_init/1/1", None, Operation::Init),
+            Err(Error::Tls(_))
+        ));
+
+        env::remove_var("K2_BASE_URL");
+    }
+
+    #[test]
+    #[serial]
+    fn trust_server_certificate_from_ca_file_only() {
+        let pki = Pki::new();
+        let port = tls_server(&pki, &pki.issue("localhost"), false, "0");
+        let ca_file = pki.ca_file();
+
+        env::set_var("K2_BASE_URL", format!("https://localhost:{}", port));
+        env::set_var("K2_CA_FILE", ca_file.path());
+        env::set_var("K2_BUILTIN_ROOTS", "false");
+        init_config();
+
+        assert_eq!(
+            Some(String::from("0")),
+            request("ct_init/1/1", None, Operation::Init)
+                .ok()
+                .map(|(_, body)| body)
+        );
+
+        env::remove_var("K2_BASE_URL");
+        env::remove_var("K2_CA_FILE");
+        env::remove_var("K2_BUILTIN_ROOTS");
+    }
+
+    #[test]
+    #[serial]
+    fn server_public_key_has_to_match_pin() {
+        let pki = Pki::new();
+        let server = pki.issue("localhost");
+        let port = tls_server(&pki, &server, false, "0");
+        let ca_file = pki.ca_file();
+        let pin = BASE64.encode(digest(&SHA256, &server.key.public_key_der()).as_ref());
+        let other_pin = BASE64.encode(&[0; 32]);
+
+        env::set_var("K2_BASE_URL", format!("https://localhost:{}", port));
+        env::set_var("K2_CA_FILE", ca_file.path());
+        env::set_var(
+            "K2_PINNED_PUBLIC_KEYS",
+            format!("sha256/{},sha256/{}", other_pin, pin),
+        );
+        init_config();
+
+        assert_eq!(
+            Some(String::from("0")),
+            request("ct_init/1/1", None, Operation::Init)
+                .ok()
+                .map(|(_, body)| body)
+        );
+
+        env::set_var("K2_PINNED_PUBLIC_KEYS", format!("sha256/{}", other_pin));
+        init_config();
+
+        assert!(matches!(
+            request("ct_init/1/1", None, Operation::Init),
+            Err(Error::Tls(_))
+        ));
+
+        env::remove_var("K2_BASE_URL");
+        env::remove_var("K2_CA_FILE");
+        env::remove_var("K2_PINNED_PUBLIC_KEYS");
+    }
+
+    #[test]
+    #[serial]
+    fn connection_is_reused() {
+        let (port, connections) = http_server("0");
+
+        env::set_var("K2_BASE_URL", format!("http://127.0.0.1:{}", port));
+        init_config();
+
+        for _ in 0..5 {
+            assert_eq!(
+                Some(String::from("0")),
+                request("ct_init/1/1", None, Operation::Init)
+                    .ok()
+                    .map(|(_, body)| body)
+            );
+        }
+        assert_eq!(1, connections.load(Ordering::SeqCst));
+
+        env::remove_var("K2_BASE_URL");
+    }
+
+    #[test]
+    #[serial]
+    fn connection_is_not_reused_if_pool_is_disabled() {
+        let (port, connections) = http_server("0");
+
+        env::set_var("K2_BASE_URL", format!("http://127.0.0.1:{}", port));
+        env::set_var("K2_POOL_SIZE", "0");
+        init_config();
+
+        for _ in 0..3 {
+            assert_eq!(
+                Some(String::from("0")),
+                request("ct_init/1/1", None, Operation::Init)
+                    .ok()
+                    .map(|(_, body)| body)
+            );
+        }
+        assert_eq!(3, connections.load(Ordering::SeqCst));
+
+        env::remove_var("K2_BASE_URL");
+        env::remove_var("K2_POOL_SIZE");
+    }
+
+    #[test]
+    #[serial]
+    fn idle_connections_are_dropped_after_timeout() {
+        let (port, connections) = http_server("0");
+
+        env::set_var("K2_BASE_URL", format!("http://127.0.0.1:{}", port));
+        env::set_var("K2_POOL_IDLE_TIMEOUT", "100");
+        init_config();
+
+        assert_eq!(
+            Some(String::from("0")),
+            request("ct_init/1/1", None, Operation::Init)
+                .ok()
+                .map(|(_, body)| body)
+        );
+        assert_eq!(
+            Some(String::from("0")),
+            request("ct_init/1/1", None, Operation::Init)
+                .ok()
+                .map(|(_, body)| body)
+        );
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(
+            Some(String::from("0")),
+            request("ct_init/1/1", None, Operation::Init)
+                .ok()
+                .map(|(_, body)| body)
+        );
+        assert_eq!(2, connections.load(Ordering::SeqCst));
+
+        env::remove_var("K2_BASE_URL");
+        env::remove_var("K2_POOL_IDLE_TIMEOUT");
+    }
+
+    #[test]
+    #[serial]
+    fn agent_is_rebuilt_if_settings_change() {
+        let (port, connections) = http_server("0");
+
+        env::set_var("K2_BASE_URL", format!("http://127.0.0.1:{}", port));
+        init_config();
+        assert_eq!(
+            Some(String::from("0")),
+            request("ct_init/1/1", None, Operation::Init)
+                .ok()
+                .map(|(_, body)| body)
+        );
+
+        env::set_var("K2_READ_TIMEOUT", "10000");
+        init_config();
+        assert_eq!(
+            Some(String::from("0")),
+            request("ct_init/1/1", None, Operation::Init)
+                .ok()
+                .map(|(_, body)| body)
+        );
+
+        assert_eq!(2, connections.load(Ordering::SeqCst));
+
+        env::remove_var("K2_BASE_URL");
+        env::remove_var("K2_READ_TIMEOUT");
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn idempotent_request_is_retried_on_server_error() {
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_string("0"))
+            .mount(&mock_server)
+            .await;
+
+        env::set_var("K2_BASE_URL", mock_server.uri());
+        env::set_var("K2_RETRY_ATTEMPTS", "3");
+        env::set_var("K2_RETRY_BACKOFF", "1");
+        init_config();
+
+        assert_eq!(
+            Some(String::from("0")),
+            request("", None, Operation::Init)
+                .ok()
+                .map(|(_, body)| body)
+        );
+        assert_eq!(3, received_requests(&mock_server).await);
+
+        env::remove_var("K2_BASE_URL");
+        env::remove_var("K2_RETRY_ATTEMPTS");
+        env::remove_var("K2_RETRY_BACKOFF");
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn retries_are_limited_by_attempts() {
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&mock_server)
+            .await;
+
+        env::set_var("K2_BASE_URL", mock_server.uri());
+        env::set_var("K2_RETRY_ATTEMPTS", "3");
+        env::set_var("K2_RETRY_BACKOFF", "1");
+        init_config();
+
+        assert!(matches!(
+            request("", None, Operation::Init),
+            Err(Error::Status(503, _))
+        ));
+        assert_eq!(3, received_requests(&mock_server).await);
+
+        env::remove_var("K2_BASE_URL");
+        env::remove_var("K2_RETRY_ATTEMPTS");
+        env::remove_var("K2_RETRY_BACKOFF");
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn client_errors_are_not_retried() {
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(400))
+            .mount(&mock_server)
+            .await;
+
+        env::set_var("K2_BASE_URL", mock_server.uri());
+        env::set_var("K2_RETRY_ATTEMPTS", "3");
+        env::set_var("K2_RETRY_BACKOFF", "1");
+        init_config();
+
+        assert!(request("", None, Operation::Init).is_err());
+        assert_eq!(1, received_requests(&mock_server).await);
+
+        env::remove_var("K2_BASE_URL");
+        env::remove_var("K2_RETRY_ATTEMPTS");
+        env::remove_var("K2_RETRY_BACKOFF");
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn sent_request_is_not_retried_unless_idempotent() {
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(3)))
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+
+        env::set_var("K2_BASE_URL", mock_server.uri());
+        env::set_var("K2_RETRY_ATTEMPTS", "5");
+        env::set_var("K2_RETRY_BACKOFF", "1");
+        env::set_var("K2_TIMEOUT", "1");
+        init_config();
+
+        assert!(matches!(
+            request("", Some(json!({})), Operation::Data),
+            Err(Error::Status(503, _))
+        ));
+        assert_eq!(1, received_requests(&mock_server).await);
+
+        assert!(matches!(
+            request("", Some(json!({})), Operation::Data),
+            Err(Error::Timeout)
+        ));
+        assert_eq!(2, received_requests(&mock_server).await);
+
+        env::remove_var("K2_BASE_URL");
+        env::remove_var("K2_RETRY_ATTEMPTS");
+        env::remove_var("K2_RETRY_BACKOFF");
+        env::remove_var("K2_TIMEOUT");
+    }
+
+    #[test]
+    #[serial]
+    fn unsent_request_is_retried_on_connection_failure() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let server = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            read_http_request(&mut stream).unwrap();
+            write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n0").unwrap();
+        });
+
+        env::set_var("K2_BASE_URL", format!("http://127.0.0.1:{}", port));
+        env::set_var("K2_RETRY_ATTEMPTS", "20");
+        env::set_var("K2_RETRY_BACKOFF", "50");
+        env::set_var("K2_RETRY_MAX_BACKOFF", "50");
+        init_config();
+
+        assert_eq!(
+            Some(String::from("0")),
+            request("", Some(json!({})), Operation::Data)
+                .ok()
+                .map(|(_, body)| body)
+        );
+        server.join().unwrap();
+
+        env::remove_var("K2_BASE_URL");
+        env::remove_var("K2_RETRY_ATTEMPTS");
+        env::remove_var("K2_RETRY_BACKOFF");
+        env::remove_var("K2_RETRY_MAX_BACKOFF");
+    }
+
+    #[test]
+    fn unsent_request_is_not_retried_on_permanent_failure() {
+        let failure = |error| Failure { error, sent: false };
+
+        assert!(is_retryable(
+            Retry::UnlessSent,
+            &failure(Error::Unreachable(String::from("refused")))
+        ));
+        assert!(!is_retryable(
+            Retry::UnlessSent,
+            &failure(Error::Tls(String::from("unknown CA")))
+        ));
+        assert!(!is_retryable(
+            Retry::UnlessSent,
+            &failure(Error::Malformed(String::from("invalid PEM")))
+        ));
+    }
+
+    #[test]
+    fn retry_delay_grows_exponentially_with_jitter() {
+        let policy = RetryPolicy {
+            attempts: 10,
+            backoff: 100,
+            max_backoff: 1000,
+        };
+
+        for (attempt, max) in &[(1, 100), (2, 200), (3, 400), (4, 800), (5, 1000), (9, 1000)] {
+            let delay = policy.delay(*attempt).as_millis() as u64;
+            assert!(delay >= max / 2 && delay <= *max, "{} ms", delay);
+        }
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn failover_to_next_k2_is_sticky() {
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_string("0"))
+            .mount(&mock_server)
+            .await;
+
+        env::set_var(
+            "K2_BASE_URL",
+            format!("{},{}", unreachable_url(), mock_server.uri()),
+        );
+        env::set_var("K2_FAILOVER_COOLDOWN", "0");
+        init_config();
+
+        for _ in 0..3 {
+            assert_eq!(
+                Some((format!("{}/", mock_server.uri()), String::from("0"))),
+                request("ct_init/1/1", None, Operation::Init).ok()
+            );
+        }
+        assert_eq!(
+            Some(format!("{}/", mock_server.uri())),
+            HEALTH.lock().active
+        );
+        assert_eq!(3, received_requests(&mock_server).await);
+
+        env::remove_var("K2_BASE_URL");
+        env::remove_var("K2_FAILOVER_COOLDOWN");
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn sent_request_does_not_fail_over() {
+        let failing_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&failing_server)
+            .await;
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_string("0"))
+            .mount(&mock_server)
+            .await;
+
+        env::set_var(
+            "K2_BASE_URL",
+            format!("{},{}", failing_server.uri(), mock_server.uri()),
+        );
+
+        for operation in [Operation::Init, Operation::Close] {
+            init_config();
+            assert!(matches!(
+                request("ct_init/1/1", None, operation),
+                Err(Error::Status(503, _))
+            ));
+        }
+        assert_eq!(0, received_requests(&mock_server).await);
+
+        env::remove_var("K2_BASE_URL");
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn failed_k2_is_skipped_during_cooldown() {
+        let failed_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_string("0"))
+            .mount(&failed_server)
+            .await;
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_string("0"))
+            .mount(&mock_server)
+            .await;
+        let failed = format!("{}/", failed_server.uri());
+
+        env::set_var(
+            "K2_BASE_URL",
+            format!("{},{}", failed_server.uri(), mock_server.uri()),
+        );
+        env::set_var("K2_FAILOVER_COOLDOWN", "60000");
+        init_config();
+        let _ = HEALTH.lock().failed.insert(failed.clone(), Instant::now());
+
+        assert_eq!(
+            Some(format!("{}/", mock_server.uri())),
+            request("ct_init/1/1", None, Operation::Init)
+                .ok()
+                .map(|(base_url, _)| base_url)
+        );
+        assert_eq!(0, received_requests(&failed_server).await);
+
+        // still tried last rather than never
+        env::set_var(
+            "K2_BASE_URL",
+            format!("{},{}", unreachable_url(), failed_server.uri()),
+        );
+        init_config();
+        let _ = HEALTH.lock().failed.insert(failed.clone(), Instant::now());
+
+        assert_eq!(
+            Some(failed),
+            request("ct_init/1/1", None, Operation::Init)
+                .ok()
+                .map(|(base_url, _)| base_url)
+        );
+
+        env::remove_var("K2_BASE_URL");
+        env::remove_var("K2_FAILOVER_COOLDOWN");
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn request_to_does_not_fail_over() {
+        let failing_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&failing_server)
+            .await;
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_string("0"))
+            .mount(&mock_server)
+            .await;
+
+        env::set_var(
+            "K2_BASE_URL",
+            format!("{},{}", failing_server.uri(), mock_server.uri()),
+        );
+        init_config();
+
+        assert!(matches!(
+            request_to(&failing_server.uri(), "", None, Operation::Init),
+            Err(Error::Status(503, _))
+        ));
+        assert_eq!(0, received_requests(&mock_server).await);
+
+        env::remove_var("K2_BASE_URL");
+    }
+
+    #[test]
+    #[serial]
+    fn requests_are_sent_through_proxy() {
+        let (port, requests) = crate::tests::proxy_server("0");
+
+        env::set_var("K2_BASE_URL", "http://k2.invalid:8088/k2/ctapi");
+        env::set_var("K2_PROXY", format!("http://127.0.0.1:{}", port));
+        env::set_var("K2_PROXY_AUTH", "user:secret");
+        init_config();
+
+        assert_eq!(
+            Some(String::from("0")),
+            request("ct_init/1/1", None, Operation::Init)
+                .ok()
+                .map(|(_, body)| body)
+        );
+
+        let requests = requests.lock();
+        assert_eq!(1, requests.len());
+        assert!(requests[0].starts_with("POST http://k2.invalid:8088/k2/ctapi/ct_init/1/1 "));
+        assert!(requests[0]
+            .to_lowercase()
+            .contains("proxy-authorization: basic dxnlcjpzzwnyzxq="));
+
+        env::remove_var("K2_BASE_URL");
+        env::remove_var("K2_PROXY");
+        env::remove_var("K2_PROXY_AUTH");
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn no_proxy_hosts_are_requested_directly() {
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_string("0"))
+            .mount(&mock_server)
+            .await;
+        let (port, requests) = crate::tests::proxy_server("1");
+
+        env::set_var("K2_BASE_URL", mock_server.uri());
+        env::set_var("K2_PROXY", format!("http://127.0.0.1:{}", port));
+        env::set_var("K2_NO_PROXY", "localhost,127.0.0.1");
+        init_config();
+
+        assert_eq!(
+            Some(String::from("0")),
+            request("ct_init/1/1", None, Operation::Init)
+                .ok()
+                .map(|(_, body)| body)
+        );
+        assert!(requests.lock().is_empty());
+
+        env::remove_var("K2_BASE_URL");
+        env::remove_var("K2_PROXY");
+        env::remove_var("K2_NO_PROXY");
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn proxy_env_variables_are_honoured_when_enabled() {
+        let mock_server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_string("0"))
+            .mount(&mock_server)
+            .await;
+        let (port, requests) = crate::tests::proxy_server("1");
+
+        env::set_var("K2_BASE_URL", mock_server.uri());
+        env::set_var("HTTP_PROXY", format!("http://127.0.0.1:{}", port));
+        init_config();
+
+        assert_eq!(
+            Some(String::from("0")),
+            request("ct_init/1/1", None, Operation::Init)
+                .ok()
+                .map(|(_, body)| body)
+        );
+        assert!(requests.lock().is_empty());
+
+        env::set_var("K2_PROXY_FROM_ENV", "true");
+        init_config();
+
+        assert_eq!(
+            Some(String::from("1")),
+            request("ct_init/1/1", None, Operation::Init)
+                .ok()
+                .map(|(_, body)| body)
+        );
+        assert_eq!(1, requests.lock().len());
+
+        env::set_var("NO_PROXY", "127.0.0.1");
+
+        assert_eq!(
+            Some(String::from("0")),
+            request("ct_init/1/1", None, Operation::Init)
+                .ok()
+                .map(|(_, body)| body)
+        );
+        assert_eq!(1, requests.lock().len());
+
+        env::remove_var("K2_BASE_URL");
+        env::remove_var("K2_PROXY_FROM_ENV");
+        env::remove_var("HTTP_PROXY");
+        env::remove_var("NO_PROXY");
+    }
+
+    #[test]
+    fn no_proxy_patterns() {
+        assert!(is_excluded("k2.example.com", "*"));
+        assert!(is_excluded("k2.example.com", "k2.example.com"));
+        assert!(is_excluded("k2.example.com", "example.com"));
+        assert!(is_excluded("k2.example.com", ".example.com"));
+        assert!(is_excluded("K2.Example.com", "*.example.com"));
+        assert!(!is_excluded("k2.example.com", "other.com"));
+        assert!(!is_excluded("notexample.com", "example.com"));
+    }
+
+    async fn received_requests(mock_server: &MockServer) -> usize {
+        mock_server
+            .received_requests()
+            .await
+            .map(|requests| requests.len())
+            .unwrap_or(0)
+    }
+
+    fn init_config() {
+        let mut config_guard = CONFIG.write();
+        *config_guard = Settings::init().unwrap();
+        drop(config_guard);
+
+        super::reset();
     }
 }
@@ -0,0 +1,295 @@
+use super::{excerpt, Error, Failure};
+use crate::CONFIG;
+use serde_json::Value;
+use std::{
+    io::{self, Read, Write},
+    os::unix::net::UnixStream,
+    time::{Duration, Instant},
+};
+
+/// Socket path of a base URL like `unix:///run/k2/ctapi.sock`.
+pub fn socket_path(base_url: &str) -> Option<String> {
+    base_url
+        .strip_prefix("unix://")
+        .map(|path| path.trim_end_matches('/').to_string())
+}
+
+/// Sends a request over the socket, speaking just enough HTTP/1.1 for the K2 REST API.
+pub fn send(
+    method: &str,
+    socket: &str,
+    path: &str,
+    request_body: Option<&Value>,
+    timeout: Option<Duration>,
+    authorization: Option<&str>,
+) -> Result<String, Failure> {
+    let read_timeout = CONFIG
+        .read()
+        .read_timeout
+        .filter(|timeout| *timeout > 0)
+        .map(Duration::from_millis);
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+    let mut stream = UnixStream::connect(socket).map_err(|why| Failure {
+        error: Error::Unreachable(format!("{}: {}", socket, why)),
+        sent: false,
+    })?;
+
+    let body = request_body.map(Value::to_string).unwrap_or_default();
+    let mut head = format!(
+        "{} /{} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n",
+        method,
+        path,
+        body.len()
+    );
+    if let Some(authorization) = authorization {
+        head.push_str(&format!("Authorization: {}\r\n", authorization));
+    }
+    head.push_str("\r\n");
+
+    let sent = |why: io::Error| Failure {
+        error: match why.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Error::Timeout,
+            _ => Error::Unreachable(why.to_string()),
+        },
+        sent: true,
+    };
+
+    stream
+        .set_write_timeout(remaining(deadline, None).map_err(sent)?)
+        .map_err(sent)?;
+    stream
+        .write_all(format!("{}{}", head, body).as_bytes())
+        .map_err(sent)?;
+
+    let mut response = Vec::new();
+    let mut chunk = [0; 1024];
+    loop {
+        stream
+            .set_read_timeout(remaining(deadline, read_timeout).map_err(sent)?)
+            .map_err(sent)?;
+        match stream.read(&mut chunk).map_err(sent)? {
+            0 => break,
+            read => response.extend_from_slice(&chunk[..read]),
+        }
+    }
+
+    parse_response(&response).map_err(|error| Failure { error, sent: true })
+}
+
+/// Time left until the deadline, bounded by the read timeout.
+fn remaining(
+    deadline: Option<Instant>,
+    read_timeout: Option<Duration>,
+) -> io::Result<Option<Duration>> {
+    let left = match deadline {
+        Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+            Some(left) if left > Duration::from_millis(0) => Some(left),
+            _ => return Err(io::ErrorKind::TimedOut.into()),
+        },
+        None => None,
+    };
+
+    Ok(match (left, read_timeout) {
+        (Some(left), Some(read_timeout)) => Some(left.min(read_timeout)),
+        (left, read_timeout) => left.or(read_timeout),
+    })
+}
+
+fn parse_response(response: &[u8]) -> Result<String, Error> {
+    let end = find(response, b"\r\n\r\n")
+        .ok_or_else(|| Error::Malformed(String::from("Incomplete response from K2")))?;
+    let head = String::from_utf8_lossy(&response[..end]);
+    let body = &response[end + 4..];
+
+    let mut lines = head.lines();
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| Error::Malformed(String::from("Invalid status line from K2")))?;
+    let header = |name: &str| {
+        lines
+            .clone()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim().to_string())
+    };
+
+    if status >= 400 {
+        debug!("Response: {}", head);
+        return Err(Error::Status(
+            status,
+            excerpt(&String::from_utf8_lossy(body)),
+        ));
+    }
+
+    let body =
+        if header("transfer-encoding").is_some_and(|value| value.eq_ignore_ascii_case("chunked")) {
+            decode_chunked(body)?
+        } else {
+            match header("content-length").map(|length| length.parse::<usize>()) {
+                Some(Ok(length)) if length <= body.len() => body[..length].to_vec(),
+                Some(_) => {
+                    return Err(Error::Malformed(String::from(
+                        "Invalid content length from K2",
+                    )))
+                }
+                None => body.to_vec(),
+            }
+        };
+
+    String::from_utf8(body)
+        .map_err(|_| Error::Malformed(String::from("Invalid UTF-8 in response from K2")))
+}
+
+fn decode_chunked(mut body: &[u8]) -> Result<Vec<u8>, Error> {
+    let malformed = || Error::Malformed(String::from("Invalid chunked response from K2"));
+
+    let mut decoded = Vec::new();
+    loop {
+        let end = find(body, b"\r\n").ok_or_else(malformed)?;
+        let size = std::str::from_utf8(&body[..end]).map_err(|_| malformed())?;
+        let size = size.split(';').next().unwrap_or(size).trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| malformed())?;
+        if size == 0 {
+            return Ok(decoded);
+        }
+
+        let rest = &body[end + 2..];
+        let chunk = rest.get(..size).ok_or_else(malformed)?;
+        decoded.extend_from_slice(chunk);
+        body = rest[size..].strip_prefix(b"\r\n").ok_or_else(malformed)?;
+    }
+}
+
+/// Position of the first occurrence of `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::parse_response;
+    use crate::{
+        http::{request, Error, Operation},
+        tests::read_http_request,
+    };
+    use antidote::Mutex;
+    use std::{env, io::Write, os::unix::net::UnixListener, sync::Arc, thread};
+    use tempfile::{tempdir, TempDir};
+
+    /// Answers each request on a socket with the given response and records it.
+    fn socket_server(response: &'static str) -> (TempDir, String, Arc<Mutex<Vec<String>>>) {
+        let folder = tempdir().unwrap();
+        let socket = folder.path().join("ctapi.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                if let Some(request) = read_http_request(&mut stream) {
+                    recorded.lock().push(request);
+                    let _ = stream.write_all(response.as_bytes());
+                }
+            }
+        });
+
+        (folder, socket.to_str().unwrap().to_string(), requests)
+    }
+
+    #[test]
+    #[serial]
+    fn request_over_unix_socket() {
+        let (_folder, socket, requests) =
+            socket_server("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n0");
+
+        env::set_var("K2_BASE_URL", format!("unix://{}", socket));
+        crate::tests::init_config_clear_map();
+
+        assert_eq!(
+            Some((format!("unix://{}/", socket), String::from("0"))),
+            request("ct_data/1/2", Some(json!({"dad": 1})), Operation::Data).ok()
+        );
+
+        let requests = requests.lock();
+        assert_eq!(1, requests.len());
+        assert!(requests[0].starts_with("POST /ct_data/1/2 HTTP/1.1\r\n"));
+        assert!(requests[0].contains("Content-Type: application/json\r\n"));
+        assert!(requests[0].ends_with("\r\n\r\n{\"dad\":1}"));
+
+        env::remove_var("K2_BASE_URL");
+    }
+
+    #[test]
+    #[serial]
+    fn error_status_over_unix_socket() {
+        let (_folder, socket, _) =
+            socket_server("HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n");
+
+        env::set_var("K2_BASE_URL", format!("unix://{}", socket));
+        crate::tests::init_config_clear_map();
+
+        assert!(matches!(
+            request("ct_init/1/2", None, Operation::Init),
+            Err(Error::Status(503, _))
+        ));
+
+        env::remove_var("K2_BASE_URL");
+    }
+
+    #[test]
+    #[serial]
+    fn missing_socket_is_unreachable() {
+        let folder = tempdir().unwrap();
+
+        env::set_var(
+            "K2_BASE_URL",
+            format!("unix://{}/missing.sock", folder.path().to_str().unwrap()),
+        );
+        crate::tests::init_config_clear_map();
+
+        assert!(matches!(
+            request("ct_init/1/2", None, Operation::Init),
+            Err(Error::Unreachable(_))
+        ));
+
+        env::remove_var("K2_BASE_URL");
+    }
+
+    #[test]
+    fn chunked_response() {
+        assert_eq!(
+            Some(String::from("{\"a\":1}")),
+            parse_response(
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\n{\"a\r\n4\r\n\":1}\r\n0\r\n\r\n"
+            )
+            .ok()
+        );
+        assert!(matches!(
+            parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n0"),
+            Err(Error::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn content_length_counts_bytes() {
+        assert_eq!(
+            Some(String::from("\u{e4}")),
+            parse_response("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n\u{e4}\u{20ac}".as_bytes())
+                .ok()
+        );
+        assert!(matches!(
+            parse_response("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n\u{e4}".as_bytes()),
+            Err(Error::Malformed(_))
+        ));
+        assert!(matches!(
+            parse_response(b"HTTP/1.1 200 OK\r\n\r\n\xFF"),
+            Err(Error::Malformed(_))
+        ));
+    }
+}
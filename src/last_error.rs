@@ -0,0 +1,112 @@
+use crate::http;
+use antidote::Mutex;
+use once_cell::sync::Lazy;
+use std::{cell::RefCell, collections::HashMap};
+
+/// Logs an error and keeps it as reason for the status the current call is about to return.
+macro_rules! reject {
+    ($code:expr, $($arg:tt)+) => {{
+        let message = format!($($arg)+);
+        error!("{}", message);
+        $crate::last_error::reason($code, message);
+    }};
+}
+
+/// Category of the last error, as returned by `K2_last_error_code`.
+#[derive(Clone, Copy)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[repr(i32)]
+pub enum Code {
+    None = 0,
+    Unreachable = 1,
+    Timeout = 2,
+    Tls = 3,
+    HttpStatus = 4,
+    MalformedResponse = 5,
+    /// Invalid call by the host, e.g., on a card terminal that has not been opened.
+    InvalidCall = 6,
+    /// The card terminal answered with an error status.
+    Status = 7,
+    Internal = 8,
+    InvalidConfiguration = 9,
+    /// K2 lost the session of the card terminal, which has been opened again.
+    SessionLost = 10,
+    /// K2 provides an API version this adapter does not support, see `preflight`.
+    IncompatibleK2 = 11,
+    /// The response does not fit into the buffer of the host.
+    ResponseTooLarge = 12,
+}
+
+impl Code {
+    pub fn of(why: &anyhow::Error) -> Self {
+        match why.downcast_ref::<http::Error>() {
+            Some(http::Error::Unreachable(_)) => Code::Unreachable,
+            Some(http::Error::Timeout) => Code::Timeout,
+            Some(http::Error::Tls(_)) => Code::Tls,
+            Some(http::Error::Status(..)) => Code::HttpStatus,
+            Some(http::Error::Malformed(_)) => Code::MalformedResponse,
+            None => Code::Internal,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct LastError {
+    code: Code,
+    message: String,
+}
+
+thread_local! {
+    /// Reason for the status of the running call.
+    static REASON: RefCell<Option<LastError>> = const { RefCell::new(None) };
+    static LAST_ERROR: RefCell<Option<LastError>> = const { RefCell::new(None) };
+}
+
+static BY_CTN: Lazy<Mutex<HashMap<u16, LastError>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn reason(code: Code, message: String) {
+    REASON.with(|reason| *reason.borrow_mut() = Some(LastError { code, message }));
+}
+
+/// Forgets the reason of a previous call before starting a new one.
+pub fn begin() {
+    REASON.with(|reason| *reason.borrow_mut() = None);
+}
+
+/// Records the outcome of a call on the ctn, keeping the reason given for a failed status.
+pub fn finish(ctn: u16, function: &str, status: i8) {
+    let reason = REASON.with(|reason| reason.borrow_mut().take());
+    if status == 0 {
+        LAST_ERROR.with(|last| *last.borrow_mut() = None);
+        let _ = BY_CTN.lock().remove(&ctn);
+        return;
+    }
+
+    let reason = reason.unwrap_or_else(|| LastError {
+        code: Code::Status,
+        message: format!("Card terminal returned status {}", status),
+    });
+    let error = LastError {
+        code: reason.code,
+        message: format!("{}(ctn {}): {}", function, ctn, reason.message),
+    };
+
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(error.clone()));
+    let _ = BY_CTN.lock().insert(ctn, error);
+}
+
+/// Code of the last error of the calling thread.
+pub fn code() -> Code {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(Code::None, |last| last.code))
+}
+
+/// Message of the last error on the ctn.
+pub fn message(ctn: u16) -> Option<String> {
+    BY_CTN.lock().get(&ctn).map(|last| last.message.clone())
+}
+
+#[cfg(test)]
+pub fn reset() {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+    BY_CTN.lock().clear();
+}
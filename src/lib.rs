@@ -17,41 +17,46 @@ extern crate serde_json;
 #[macro_use]
 extern crate serial_test;
 
+mod apdu;
+#[macro_use]
+mod last_error;
+
+mod backend;
 mod ctapi;
+mod ffi;
 mod http;
 mod logging;
+mod preflight;
 mod settings;
 #[cfg(test)]
 mod tests;
+mod tls;
+mod version;
 
 use crate::ctapi::close::close;
 use crate::ctapi::data::data;
 use crate::ctapi::init::init;
 use crate::ctapi::status::Status;
+use crate::last_error::Code;
 use crate::settings::Settings;
-use antidote::RwLock;
+use antidote::{Mutex, RwLock};
 use once_cell::sync::Lazy;
-use std::panic;
+use std::{collections::HashMap, os::raw::c_char, slice};
+
+static CONFIG: Lazy<RwLock<Settings>> = Lazy::new(|| RwLock::new(settings::load()));
 
-static CONFIG: Lazy<RwLock<Settings>> =
-    Lazy::new(|| RwLock::new(Settings::init().expect("Failed to init configuration!")));
+static AGENTS: Lazy<Mutex<HashMap<Option<String>, http::PooledAgent>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
 
 #[no_mangle]
 pub extern "system" fn CT_init(ctn: u16, pn: u16) -> i8 {
-    logging::init();
-
-    debug!("CT_init(ctn: {}, pn: {})", ctn, pn);
-    let status: i8 = match init(ctn, pn) {
-        Ok(status) => status.into(),
-        Err(why) => {
-            error!("Failure during CT_init!");
-            debug!("{}", why);
-            Status::ERR_HTSI.into()
+    ffi::guard("CT_init", ctn, || {
+        debug!("CT_init(ctn: {}, pn: {})", ctn, pn);
+        match init(ctn, pn) {
+            Ok(status) => status.into(),
+            Err(why) => failure("CT_init", why),
         }
-    };
-
-    debug!("Returning {}", status);
-    status
+    })
 }
 
 #[no_mangle]
@@ -64,62 +69,141 @@ pub extern "system" fn CT_data(
     lenr: *mut u16,
     response: *mut u8,
 ) -> i8 {
-    logging::init();
+    ffi::guard("CT_data", ctn, || {
+        let null = [
+            ("dad", dad.is_null()),
+            ("sad", sad.is_null()),
+            ("command", command.is_null()),
+            ("lenr", lenr.is_null()),
+            ("response", response.is_null()),
+        ];
+        if let Some((name, _)) = null.iter().find(|(_, null)| *null) {
+            reject!(
+                Code::InvalidCall,
+                "Null pointer passed into CT_data() as {}",
+                name
+            );
+            return Status::ERR_HTSI.into();
+        }
 
-    if dad.is_null() {
-        error!("Null pointer passed into CT_data() as dad");
-        return Status::ERR_HTSI.into();
-    }
+        if lenc == 0 {
+            reject!(Code::InvalidCall, "Empty command passed into CT_data()");
+            return Status::ERR_INVALID.into();
+        }
 
-    if sad.is_null() {
-        error!("Null pointer passed into CT_data() as sad");
-        return Status::ERR_HTSI.into();
-    }
+        debug!("CT_data(ctn: {})", ctn);
+        match data(ctn, dad, sad, lenc, command, lenr, response) {
+            Ok(status) => status.into(),
+            Err(why) => failure("CT_data", why),
+        }
+    })
+}
 
-    if lenr.is_null() {
-        error!("Null pointer passed into CT_data() as lenr");
-        return Status::ERR_HTSI.into();
-    }
+#[no_mangle]
+pub extern "system" fn CT_close(ctn: u16) -> i8 {
+    ffi::guard("CT_close", ctn, || {
+        debug!("CT_close(ctn: {})", ctn);
+        match close(ctn) {
+            Ok(status) => status.into(),
+            Err(why) => failure("CT_close", why),
+        }
+    })
+}
 
-    if response.is_null() {
-        error!("Null pointer passed into CT_data() as response");
-        return Status::ERR_HTSI.into();
-    }
+/// Closes every card terminal left open, e.g. before the host exits, within `shutdown_timeout`.
+/// Returns the number of card terminals that are still open.
+#[no_mangle]
+pub extern "system" fn K2_shutdown() -> u16 {
+    ffi::catch(u16::MAX, || {
+        logging::init();
+        ctapi::close::close_all().min(u16::MAX as usize) as u16
+    })
+}
 
-    debug!("CT_data(ctn: {})", ctn);
-    let status: i8 =
-        match panic::catch_unwind(|| data(ctn, dad, sad, lenc, command, lenr, response)) {
-            Ok(Ok(status)) => status.into(),
-            Ok(Err(why)) => {
-                error!("Failure during CT_data!");
-                debug!("{}", why);
-                Status::ERR_HTSI.into()
-            }
-            Err(why) => {
-                error!("Caught panic!");
-                debug!("{:#?}", why);
-                Status::ERR_HTSI.into()
-            }
-        };
+/// Copies the message of the last failed call on the ctn as NUL terminated string into the
+/// buffer, truncated to fit. Returns the length of the whole message, 0 if there is none.
+#[no_mangle]
+pub extern "system" fn K2_last_error(ctn: u16, buffer: *mut u8, length: u16) -> u16 {
+    ffi::catch(0, || {
+        copy_string(
+            &last_error::message(ctn).unwrap_or_default(),
+            buffer,
+            length,
+        )
+    })
+}
 
-    debug!("Returning {}", status);
-    status
+/// Code of the last error of the calling thread, see `last_error::Code`.
+#[no_mangle]
+pub extern "system" fn K2_last_error_code() -> i32 {
+    ffi::catch(Code::Internal as i32, || last_error::code() as i32)
 }
 
+/// Version of the library as NUL terminated string.
 #[no_mangle]
-pub extern "system" fn CT_close(ctn: u16) -> i8 {
-    logging::init();
+pub extern "system" fn K2_version() -> *const c_char {
+    version::VERSION.as_ptr() as *const c_char
+}
 
-    debug!("CT_close(ctn: {})", ctn);
-    let status = match close(ctn) {
-        Ok(status) => status.into(),
-        Err(why) => {
-            error!("Failure during CT_close!");
+/// Copies version, git revision, cargo features and configuration source as NUL terminated
+/// `key=value` lines into the buffer, see `K2_last_error`.
+#[no_mangle]
+pub extern "system" fn K2_build_info(buffer: *mut u8, length: u16) -> u16 {
+    ffi::catch(0, || copy_string(&version::build_info(), buffer, length))
+}
+
+/// Checks the K2 the next card terminal would be opened on unless cached, and copies the
+/// diagnosis into the buffer, see `K2_last_error`. Returns the verdict, see `preflight::Verdict`.
+#[no_mangle]
+pub extern "system" fn K2_preflight(buffer: *mut u8, length: u16) -> i32 {
+    ffi::catch(preflight::Verdict::Failed as i32, || {
+        logging::init();
+        let outcome = preflight::outcome(&http::active().unwrap_or_default());
+        let _ = copy_string(&outcome.diagnosis, buffer, length);
+        outcome.verdict as i32
+    })
+}
+
+/// Copies the string NUL terminated into the buffer, truncated to fit. Returns the length of the
+/// whole string.
+fn copy_string(string: &str, buffer: *mut u8, length: u16) -> u16 {
+    if !buffer.is_null() && length > 0 {
+        let buffer = unsafe { slice::from_raw_parts_mut(buffer, length as usize) };
+        let copied = string.len().min(buffer.len() - 1);
+        buffer[..copied].copy_from_slice(&string.as_bytes()[..copied]);
+        buffer[copied] = 0;
+    }
+
+    string.len().min(u16::MAX as usize) as u16
+}
+
+fn failure(function: &str, why: anyhow::Error) -> i8 {
+    last_error::reason(Code::of(&why), format!("{:#}", why));
+    match why.downcast_ref::<http::Error>() {
+        Some(http_error) => {
+            match http_error {
+                http::Error::Unreachable(_) => {
+                    error!("Failure during {}! K2 unreachable.", function)
+                }
+                http::Error::Timeout => error!("Failure during {}! K2 timed out.", function),
+                http::Error::Tls(_) => {
+                    error!("Failure during {}! TLS handshake with K2 failed.", function)
+                }
+                http::Error::Status(code, _) => error!(
+                    "Failure during {}! K2 responded with status code {}.",
+                    function, code
+                ),
+                http::Error::Malformed(_) => {
+                    error!("Failure during {}! K2 sent malformed response.", function)
+                }
+            }
+            debug!("{}", http_error);
+            http_error.status().into()
+        }
+        None => {
+            error!("Failure during {}!", function);
             debug!("{}", why);
             Status::ERR_HTSI.into()
         }
-    };
-
-    debug!("Returning {}", status);
-    status
+    }
 }
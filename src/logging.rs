@@ -11,7 +11,13 @@ const FILENAME: &str = "libctehxk2.log";
 
 pub fn init() {
     INIT.call_once(|| {
-        fern::Dispatch::new()
+        let (output, failure) = match determine_logger() {
+            Ok(output) => (output, None),
+            Err(why) => (std::io::stdout().into(), Some(why)),
+        };
+
+        // the host may have installed a logger already
+        let applied = fern::Dispatch::new()
             .format(|out, message, record| {
                 out.finish(format_args!(
                     "{}[{}][{}] {}",
@@ -23,19 +29,23 @@ pub fn init() {
             })
             .level(log::LevelFilter::Error)
             .level_for("ctehxk2", determine_log_level())
-            .chain(determine_logger())
-            .apply()
-            .expect("Failed to initialize logging!");
+            .chain(output)
+            .apply();
+        if applied.is_err() {
+            return;
+        }
+
+        if let Some(why) = failure {
+            error!("Failed to open log file, logging to STDOUT: {}", why);
+        }
         info!("Logging initialized!");
     })
 }
 
-fn determine_logger() -> fern::Output {
+fn determine_logger() -> std::io::Result<fern::Output> {
     match &CONFIG.read().log_path {
-        Some(path) => fern::log_file(format!("{}{}", path, FILENAME))
-            .expect("Failed to open log file!")
-            .into(),
-        None => std::io::stdout().into(),
+        Some(path) => Ok(fern::log_file(format!("{}{}", path, FILENAME))?.into()),
+        None => Ok(std::io::stdout().into()),
     }
 }
 
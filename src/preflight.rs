@@ -0,0 +1,363 @@
+use crate::backend;
+use crate::http::{self, Operation};
+use crate::CONFIG;
+use antidote::Mutex;
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Major version of the K2 API this adapter is written against.
+const SUPPORTED_API_VERSION: u64 = 1;
+
+/// Whether CT_init checks the API version of the K2 before it opens a card terminal on it.
+#[derive(Clone, Copy, Deserialize)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[serde(rename_all = "kebab-case")]
+pub enum Policy {
+    Off,
+    /// Logs the diagnosis only.
+    Warn,
+    /// Refuses to open card terminals on an incompatible K2.
+    Enforce,
+}
+
+/// Result of the check, as returned by `K2_preflight`.
+#[derive(Clone, Copy)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[repr(i32)]
+pub enum Verdict {
+    NotRun = 0,
+    Compatible = 1,
+    Incompatible = 2,
+    /// K2 could not be asked or gave no usable answer, the check is repeated after a while.
+    Failed = 3,
+}
+
+#[derive(Clone)]
+pub struct Outcome {
+    pub verdict: Verdict,
+    pub diagnosis: String,
+}
+
+struct Checked {
+    outcome: Outcome,
+    at: Instant,
+}
+
+/// Time a failed check is cached, so an unreachable K2 is not asked on every CT_init.
+const FAILED_CHECK_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Outcome of the check per base URL, K2 instances failed over to may differ in version.
+static CHECKED: Lazy<Mutex<HashMap<String, Checked>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Checks the K2 the next card terminal would be opened on, so a misconfigured or unreachable
+/// K2 is diagnosed before CT_init fails. Returns the diagnosis if CT_init has to refuse because
+/// of an incompatible K2.
+pub fn before_open() -> Option<String> {
+    match CONFIG.read().preflight {
+        Policy::Off => None,
+        _ => http::active().and_then(|base_url| refusal(&base_url)),
+    }
+}
+
+/// Checks the K2 a card terminal has been opened on, which differs from the one checked before
+/// if CT_init failed over to it. Returns the diagnosis if CT_init has to refuse.
+pub fn after_open(base_url: &str) -> Option<String> {
+    match CONFIG.read().preflight {
+        Policy::Off => None,
+        _ => refusal(base_url),
+    }
+}
+
+fn refusal(base_url: &str) -> Option<String> {
+    let outcome = outcome(base_url);
+    match (CONFIG.read().preflight, outcome.verdict) {
+        (Policy::Enforce, Verdict::Incompatible) => Some(outcome.diagnosis),
+        _ => None,
+    }
+}
+
+/// Result of the check of the K2 at the base URL, which is run unless cached.
+pub fn outcome(base_url: &str) -> Outcome {
+    if let Some(checked) = CHECKED.lock().get(base_url) {
+        if !matches!(checked.outcome.verdict, Verdict::Failed)
+            || checked.at.elapsed() < FAILED_CHECK_BACKOFF
+        {
+            return checked.outcome.clone();
+        }
+    }
+
+    // concurrent callers may check at the same time, rather than waiting for a slow K2
+    let outcome = check(base_url);
+    match outcome.verdict {
+        Verdict::Compatible | Verdict::NotRun => info!("{}", outcome.diagnosis),
+        Verdict::Incompatible | Verdict::Failed => error!("{}", outcome.diagnosis),
+    }
+
+    let _ = CHECKED.lock().insert(
+        base_url.to_string(),
+        Checked {
+            outcome: outcome.clone(),
+            at: Instant::now(),
+        },
+    );
+    outcome
+}
+
+fn check(base_url: &str) -> Outcome {
+    let (kind, path, pointer) = {
+        let config = CONFIG.read();
+        (
+            config.backend,
+            config.preflight_path.clone(),
+            config.preflight_version_pointer.clone(),
+        )
+    };
+    let path = match path {
+        Some(path) if matches!(kind, backend::Kind::Rest) => path,
+        Some(_) => {
+            return Outcome {
+                verdict: Verdict::NotRun,
+                diagnosis: String::from("Preflight skipped, K2 is not used by this backend."),
+            }
+        }
+        None => {
+            return Outcome {
+                verdict: Verdict::NotRun,
+                diagnosis: String::from("Preflight skipped, preflight_path is not configured."),
+            }
+        }
+    };
+
+    let response = match http::request_to(base_url, &path, None, Operation::Info) {
+        Ok(response) => response,
+        Err(why) => {
+            return Outcome {
+                verdict: Verdict::Failed,
+                diagnosis: format!("Preflight failed to request {}{}: {}", base_url, path, why),
+            }
+        }
+    };
+
+    let api_version = match version(&response, pointer.as_deref()) {
+        Some(api_version) => api_version,
+        None => {
+            return Outcome {
+                verdict: Verdict::Failed,
+                diagnosis: format!("Preflight found no API version in {}{}.", base_url, path),
+            }
+        }
+    };
+
+    let major = api_version
+        .split('.')
+        .next()
+        .and_then(|major| major.trim().parse::<u64>().ok());
+    match major {
+        Some(SUPPORTED_API_VERSION) => Outcome {
+            verdict: Verdict::Compatible,
+            diagnosis: format!(
+                "K2 at {} provides API version {}, which is supported.",
+                base_url, api_version
+            ),
+        },
+        Some(_) => Outcome {
+            verdict: Verdict::Incompatible,
+            diagnosis: format!(
+                "K2 at {} provides API version {}, but only {}.x is supported.",
+                base_url, api_version, SUPPORTED_API_VERSION
+            ),
+        },
+        None => Outcome {
+            verdict: Verdict::Failed,
+            diagnosis: format!(
+                "K2 at {} reported the unknown API version '{}'.",
+                base_url, api_version
+            ),
+        },
+    }
+}
+
+/// API version from the response, at the JSON pointer if configured, else the whole body.
+fn version(response: &str, pointer: Option<&str>) -> Option<String> {
+    let version = match pointer {
+        Some(pointer) => match serde_json::from_str::<Value>(response)
+            .ok()?
+            .pointer(pointer)?
+        {
+            Value::String(version) => version.clone(),
+            Value::Number(version) => version.to_string(),
+            _ => return None,
+        },
+        None => response.trim().to_string(),
+    };
+
+    Some(version).filter(|version| !version.is_empty())
+}
+
+#[cfg(test)]
+pub fn reset() {
+    CHECKED.lock().clear();
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{outcome, version, Verdict};
+    use crate::{
+        ctapi::{init::init, status::Status, MAP},
+        settings::Settings,
+    };
+    use serde_json::json;
+    use std::env::{remove_var, set_var};
+    use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+    async fn k2(api_version: &str, checks: u64) -> MockServer {
+        let mock_server = MockServer::start().await;
+        Mock::given(matchers::method("GET"))
+            .and(matchers::path("/version"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "apiVersion": api_version
+            })))
+            .expect(checks)
+            .mount(&mock_server)
+            .await;
+        Mock::given(matchers::method("POST"))
+            .and(matchers::path_regex("^/ct_init|^/ct_close"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("0"))
+            .mount(&mock_server)
+            .await;
+        mock_server
+    }
+
+    fn configure(base_url: &str, policy: &str) {
+        set_var("K2_BASE_URL", base_url);
+        set_var("K2_PREFLIGHT", policy);
+        set_var("K2_PREFLIGHT_PATH", "version");
+        set_var("K2_PREFLIGHT_VERSION_POINTER", "/apiVersion");
+        crate::tests::init_config_clear_map();
+    }
+
+    fn unconfigure() {
+        remove_var("K2_BASE_URL");
+        remove_var("K2_PREFLIGHT");
+        remove_var("K2_PREFLIGHT_PATH");
+        remove_var("K2_PREFLIGHT_VERSION_POINTER");
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn compatible_k2_is_cached() {
+        let mock_server = k2("1.4.2", 1).await;
+        configure(&mock_server.uri(), "enforce");
+
+        assert_eq!(Some(Status::OK), init(1, 1).ok());
+        assert_eq!(Some(Status::OK), init(2, 1).ok());
+        let outcome = outcome(&format!("{}/", mock_server.uri()));
+        assert_eq!(Verdict::Compatible, outcome.verdict);
+        assert!(outcome.diagnosis.contains("API version 1.4.2"));
+
+        unconfigure();
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn incompatible_k2_is_refused_if_enforced() {
+        let mock_server = k2("2.0", 1).await;
+        configure(&mock_server.uri(), "enforce");
+
+        assert_eq!(Some(Status::ERR_HTSI), init(1, 1).ok());
+        assert!(!MAP.read().contains_key(&1));
+        let requests = mock_server.received_requests().await.unwrap();
+        assert_eq!(
+            vec!["/version"],
+            requests
+                .iter()
+                .map(|request| request.url.path())
+                .collect::<Vec<_>>()
+        );
+
+        set_var("K2_PREFLIGHT", "warn");
+        *crate::CONFIG.write() = crate::settings::load();
+        assert_eq!(Some(Status::OK), init(1, 1).ok());
+
+        unconfigure();
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn outcome_per_k2() {
+        let old = k2("0.9", 1).await;
+        let new = k2("1.0", 1).await;
+        configure(&format!("{},{}", old.uri(), new.uri()), "warn");
+
+        assert_eq!(
+            Verdict::Incompatible,
+            outcome(&format!("{}/", old.uri())).verdict
+        );
+        assert_eq!(
+            Verdict::Compatible,
+            outcome(&format!("{}/", new.uri())).verdict
+        );
+
+        unconfigure();
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn failed_check_is_cached() {
+        let mock_server = MockServer::start().await;
+        Mock::given(matchers::any())
+            .respond_with(ResponseTemplate::new(503))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        configure(&mock_server.uri(), "warn");
+
+        let base_url = format!("{}/", mock_server.uri());
+        assert_eq!(Verdict::Failed, outcome(&base_url).verdict);
+        assert_eq!(Verdict::Failed, outcome(&base_url).verdict);
+        assert!(outcome(&base_url)
+            .diagnosis
+            .starts_with("Preflight failed to request"));
+
+        unconfigure();
+    }
+
+    #[test]
+    #[serial]
+    fn unreachable_k2_is_diagnosed_on_init() {
+        configure("http://127.0.0.1:65432", "warn");
+
+        assert!(init(1, 1).is_err());
+        let outcome = outcome("http://127.0.0.1:65432/");
+        assert_eq!(Verdict::Failed, outcome.verdict);
+        assert!(outcome
+            .diagnosis
+            .starts_with("Preflight failed to request http://127.0.0.1:65432/version"));
+
+        unconfigure();
+    }
+
+    #[test]
+    fn version_from_response() {
+        assert_eq!(Some(String::from("1.2")), version("1.2\n", None));
+        assert_eq!(
+            Some(String::from("2")),
+            version("{\"api\":{\"major\":2}}", Some("/api/major"))
+        );
+        assert_eq!(None, version("{\"api\":{}}", Some("/api/major")));
+        assert_eq!(None, version("", None));
+    }
+
+    #[test]
+    #[serial]
+    fn preflight_requires_path() {
+        set_var("K2_PREFLIGHT", "warn");
+        assert!(Settings::init().is_err());
+        remove_var("K2_PREFLIGHT");
+    }
+}
@@ -1,5 +1,12 @@
+use crate::{apdu, backend, ctapi::status::Status, preflight, tls};
+use antidote::RwLock;
 use config::{Config, Environment, File};
-use std::path::{Path, MAIN_SEPARATOR};
+use once_cell::sync::Lazy;
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    path::{Path, MAIN_SEPARATOR},
+};
 use url::Url;
 
 #[cfg(windows)]
@@ -7,49 +14,189 @@ const CFG_FILE: &str = "ctehxk2";
 #[cfg(unix)]
 const CFG_FILE: &str = "libctehxk2";
 
+/// Extensions of the config file formats, in the order they are looked for.
+const CFG_EXTENSIONS: [&str; 4] = ["json", "yaml", "yml", "ini"];
+
+/// Config file and environment variables the settings have been read from.
+static SOURCE: Lazy<RwLock<String>> = Lazy::new(|| RwLock::new(String::from("defaults")));
+
+/// Why the configuration could not be read, if so.
+static INVALID: Lazy<RwLock<Option<String>>> = Lazy::new(|| RwLock::new(None));
+
+/// Card terminal a ctn of the host is mapped to.
+#[derive(Clone, Copy, Deserialize)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+pub struct TerminalMapping {
+    pub ctn: u16,
+    pub pn: u16,
+}
+
 #[derive(Deserialize)]
 #[cfg_attr(test, derive(Debug, PartialEq))]
 pub struct Settings {
     pub timeout: Option<u64>,
-    pub base_url: String,
+    pub connect_timeout: Option<u64>,
+    pub read_timeout: Option<u64>,
+    pub init_timeout: Option<u64>,
+    pub data_timeout: Option<u64>,
+    pub close_timeout: Option<u64>,
+    pub shutdown_timeout: u64,
+    pub backend: backend::Kind,
+    pub base_url: Vec<String>,
     pub log_level: String,
     pub log_path: Option<String>,
+    pub log_apdu: apdu::Policy,
+    pub preflight: preflight::Policy,
+    pub preflight_path: Option<String>,
+    pub preflight_version_pointer: Option<String>,
     pub ctn: Option<u16>,
     pub pn: Option<u16>,
+    pub terminals: Vec<TerminalMapping>,
+    pub status_on_unreachable: Status,
+    pub status_on_timeout: Status,
+    pub status_on_http_error: Status,
+    pub status_on_malformed: Status,
+    pub status_on_session_lost: Status,
+    pub session_lost_status_codes: Vec<u16>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub client_pkcs12: Option<String>,
+    pub client_pkcs12_password: Option<String>,
+    pub ca_file: Option<String>,
+    pub builtin_roots: bool,
+    pub pinned_public_keys: Vec<String>,
+    pub pool_size: usize,
+    pub pool_idle_timeout: u64,
+    pub retry_attempts: u32,
+    pub retry_backoff: u64,
+    pub retry_max_backoff: u64,
+    pub failover_cooldown: u64,
+    pub proxy: Option<String>,
+    pub proxy_auth: Option<String>,
+    pub no_proxy: Vec<String>,
+    pub proxy_from_env: bool,
+    pub auth_user: Option<String>,
+    pub auth_password: Option<String>,
+    pub auth_token: Option<String>,
+    pub oauth2_token_url: Option<String>,
+    pub oauth2_client_id: Option<String>,
+    pub oauth2_client_secret: Option<String>,
+    pub oauth2_scope: Option<String>,
+    pub oauth2_timeout: u64,
+    pub sim_card_image: Option<String>,
+    pub record_path: Option<String>,
+    pub journal_path: Option<String>,
+    pub replay_path: Option<String>,
 }
 
 impl Settings {
-    pub fn init() -> anyhow::Result<Self> {
-        let mut settings = Config::new();
+    /// Settings without config file and environment variables.
+    pub fn defaults() -> Self {
+        Settings::default_config()
+            .try_into()
+            .expect("Failed to read default settings!")
+    }
 
-        // set defaults
+    fn default_config() -> Config {
+        let mut settings = Config::new();
         let _ = settings
-            .set_default("base_url", "http://localhost:8088/k2/ctapi/")
+            .set_default("backend", "rest")
+            .expect("Failed to set default for backend!")
+            .set_default("base_url", vec!["http://localhost:8088/k2/ctapi/"])
             .expect("Failed to set default for base_url!")
             .set_default("log_level", "Error")
-            .expect("Failed to set default for log_level!");
+            .expect("Failed to set default for log_level!")
+            .set_default("log_apdu", "header-only")
+            .expect("Failed to set default for log_apdu!")
+            .set_default("preflight", "off")
+            .expect("Failed to set default for preflight!")
+            .set_default("status_on_unreachable", "ERR_HTSI")
+            .expect("Failed to set default for status_on_unreachable!")
+            .set_default("status_on_timeout", "ERR_HTSI")
+            .expect("Failed to set default for status_on_timeout!")
+            .set_default("status_on_http_error", "ERR_HTSI")
+            .expect("Failed to set default for status_on_http_error!")
+            .set_default("status_on_malformed", "ERR_HTSI")
+            .expect("Failed to set default for status_on_malformed!")
+            .set_default("status_on_session_lost", "ERR_CT")
+            .expect("Failed to set default for status_on_session_lost!")
+            .set_default("session_lost_status_codes", vec![404i64, 410])
+            .expect("Failed to set default for session_lost_status_codes!")
+            .set_default("builtin_roots", true)
+            .expect("Failed to set default for builtin_roots!")
+            .set_default("pinned_public_keys", Vec::<String>::new())
+            .expect("Failed to set default for pinned_public_keys!")
+            .set_default("pool_size", 4)
+            .expect("Failed to set default for pool_size!")
+            .set_default("pool_idle_timeout", 30000)
+            .expect("Failed to set default for pool_idle_timeout!")
+            .set_default("retry_attempts", 1)
+            .expect("Failed to set default for retry_attempts!")
+            .set_default("retry_backoff", 100)
+            .expect("Failed to set default for retry_backoff!")
+            .set_default("retry_max_backoff", 2000)
+            .expect("Failed to set default for retry_max_backoff!")
+            .set_default("failover_cooldown", 30000)
+            .expect("Failed to set default for failover_cooldown!")
+            .set_default("shutdown_timeout", 1000)
+            .expect("Failed to set default for shutdown_timeout!")
+            .set_default("oauth2_timeout", 5000)
+            .expect("Failed to set default for oauth2_timeout!")
+            .set_default("no_proxy", Vec::<String>::new())
+            .expect("Failed to set default for no_proxy!")
+            .set_default("proxy_from_env", false)
+            .expect("Failed to set default for proxy_from_env!")
+            .set_default("terminals", Vec::<String>::new())
+            .expect("Failed to set default for terminals!");
+        settings
+    }
+
+    pub fn init() -> anyhow::Result<Self> {
+        let mut settings = Settings::default_config();
 
         // merge with optional config file and env variables
         let _ = settings
-            .merge(File::with_name(CFG_FILE).required(false))
-            .expect("Failed to merge config file!")
-            .merge(Environment::with_prefix("K2").ignore_empty(true))
-            .expect("Failed to merge env variables!");
+            .merge(File::with_name(CFG_FILE).required(false))?
+            .merge(Environment::with_prefix("K2").ignore_empty(true))?;
+        *SOURCE.write() = describe_source();
+
+        // accept base_url as list or comma separated string
+        let base_urls = match settings.get::<Vec<String>>("base_url") {
+            Ok(urls) => urls,
+            Err(_) => settings
+                .get::<String>("base_url")?
+                .split(',')
+                .map(String::from)
+                .collect(),
+        };
 
         // force trailing slash for base_url
-        if let Ok(mut url) = settings.get::<String>("base_url") {
-            let _ = Url::parse(&url)?; // check url
+        let mut normalized = Vec::new();
+        for url in base_urls
+            .iter()
+            .map(|url| url.trim())
+            .filter(|url| !url.is_empty())
+        {
+            if Url::parse(url)?.scheme() == "unix" && cfg!(not(unix)) {
+                bail!("unix sockets are not supported on this platform");
+            }
 
-            if !url.trim().ends_with('/') {
+            let mut url = url.to_string();
+            if !url.ends_with('/') {
                 url.push('/');
-                let _ = settings.set("base_url", url);
             }
+            normalized.push(url);
+        }
+
+        if normalized.is_empty() {
+            bail!("base_url is empty");
         }
+        let _ = settings.set("base_url", normalized);
 
         // force trailing slash for log_path
         if let Ok(Some(mut path)) = settings.get::<Option<String>>("log_path") {
             if !Path::new(&path).exists() {
-                bail!("log_path does not exists");
+                bail!("log_path does not exist");
             }
 
             if !path.trim().ends_with(MAIN_SEPARATOR) {
@@ -69,34 +216,294 @@ impl Settings {
             let _ = settings.set("pn", None::<String>);
         }
 
+        // accept terminals as list or comma separated ctn:pn pairs
+        if let Ok(terminals) = settings.get::<String>("terminals") {
+            let mut mappings = Vec::new();
+            for terminal in split_list(&terminals) {
+                let (ctn, pn) = match terminal.split_once(':') {
+                    Some((ctn, pn)) => (ctn.trim().parse::<u16>()?, pn.trim().parse::<u16>()?),
+                    None => bail!("terminals have to be given as ctn:pn"),
+                };
+                let mut mapping = HashMap::new();
+                let _ = mapping.insert(String::from("ctn"), i64::from(ctn));
+                let _ = mapping.insert(String::from("pn"), i64::from(pn));
+                mappings.push(mapping);
+            }
+            let _ = settings.set("terminals", mappings);
+        }
+
+        let mut ctns = HashSet::new();
+        for terminal in settings.get::<Vec<TerminalMapping>>("terminals")? {
+            if !ctns.insert(terminal.ctn) {
+                bail!("terminals contain ctn {} more than once", terminal.ctn);
+            }
+        }
+
+        // check client certificate and card image files
+        for key in &[
+            "client_cert",
+            "client_key",
+            "client_pkcs12",
+            "sim_card_image",
+            "replay_path",
+        ] {
+            if let Ok(Some(path)) = settings.get::<Option<String>>(key) {
+                if !Path::new(&path).is_file() {
+                    bail!("{} does not exist", key);
+                }
+            }
+        }
+
+        let optional = |key: &str| settings.get::<Option<String>>(key).ok().flatten();
+        match (
+            optional("client_cert"),
+            optional("client_key"),
+            optional("client_pkcs12"),
+        ) {
+            (Some(_), None, _) | (None, Some(_), _) => {
+                bail!("client_cert and client_key have to be set together")
+            }
+            (Some(_), Some(_), Some(_)) => {
+                bail!("client_pkcs12 cannot be combined with client_cert and client_key")
+            }
+            _ => {}
+        }
+
+        if matches!(settings.get("backend")?, backend::Kind::Replay)
+            && optional("replay_path").is_none()
+        {
+            bail!("backend replay requires replay_path");
+        }
+
+        // allow a single authentication scheme towards K2
+        let schemes = [
+            optional("auth_user").or_else(|| optional("auth_password")),
+            optional("auth_token"),
+            optional("oauth2_token_url"),
+        ];
+        if schemes.iter().flatten().count() > 1 {
+            bail!("auth_user, auth_token and oauth2_token_url cannot be combined");
+        }
+
+        if let Some(url) = optional("oauth2_token_url") {
+            let _ = Url::parse(&url)?; // check url
+
+            if optional("oauth2_client_id").is_none() || optional("oauth2_client_secret").is_none()
+            {
+                bail!("oauth2_token_url requires oauth2_client_id and oauth2_client_secret");
+            }
+        }
+
+        // check trust anchors for https
+        if let Ok(Some(path)) = settings.get::<Option<String>>("ca_file") {
+            let _ = tls::read_ca_file(&path)?;
+        } else if !settings.get::<bool>("builtin_roots")? {
+            bail!("builtin_roots cannot be disabled without ca_file");
+        }
+
+        // split pinned_public_keys given as comma separated list
+        if let Ok(pins) = settings.get::<String>("pinned_public_keys") {
+            let _ = settings.set("pinned_public_keys", split_list(&pins));
+        }
+
+        for pin in settings.get::<Vec<String>>("pinned_public_keys")? {
+            let _ = tls::decode_pin(&pin)?;
+        }
+
+        // check proxy and split no_proxy given as comma separated list
+        if let Ok(Some(proxy)) = settings.get::<Option<String>>("proxy") {
+            let _ = ureq::Proxy::new(&proxy)?;
+        }
+
+        if let Ok(Some(auth)) = settings.get::<Option<String>>("proxy_auth") {
+            if !auth.contains(':') {
+                bail!("proxy_auth has to be given as user:password");
+            }
+        }
+
+        if let Ok(hosts) = settings.get::<String>("no_proxy") {
+            let _ = settings.set("no_proxy", split_list(&hosts));
+        }
+
+        // accept session_lost_status_codes as list or comma separated string
+        if let Ok(codes) = settings.get::<String>("session_lost_status_codes") {
+            let mut parsed = Vec::new();
+            for code in split_list(&codes) {
+                parsed.push(i64::from(code.parse::<u16>()?));
+            }
+            let _ = settings.set("session_lost_status_codes", parsed);
+        }
+
+        if !matches!(settings.get("preflight")?, preflight::Policy::Off)
+            && settings.get::<Option<String>>("preflight_path")?.is_none()
+        {
+            bail!("preflight requires preflight_path");
+        }
+
+        if settings.get::<u32>("retry_attempts")? == 0 {
+            bail!("retry_attempts has to be at least 1");
+        }
+
+        // a hanging token endpoint must not block every request to K2
+        if settings.get::<u64>("oauth2_timeout")? == 0 {
+            bail!("oauth2_timeout has to be at least 1");
+        }
+
+        // restrict error mapping to status codes describing a failure
+        for key in &[
+            "status_on_unreachable",
+            "status_on_timeout",
+            "status_on_http_error",
+            "status_on_malformed",
+            "status_on_session_lost",
+        ] {
+            match settings.get::<Status>(key)? {
+                Status::ERR_TRANS | Status::ERR_CT | Status::ERR_HOST | Status::ERR_HTSI => {}
+                _ => bail!(
+                    "{} must be one of ERR_TRANS, ERR_CT, ERR_HOST or ERR_HTSI",
+                    key
+                ),
+            }
+        }
+
         settings.try_into().map_err(anyhow::Error::from)
     }
 }
 
+/// Settings from the configuration, or the defaults if it is invalid, see `invalid`.
+pub fn load() -> Settings {
+    match Settings::init() {
+        Ok(settings) => {
+            *INVALID.write() = None;
+            settings
+        }
+        Err(why) => {
+            *INVALID.write() = Some(format!("{:#}", why));
+            Settings::defaults()
+        }
+    }
+}
+
+/// Reason the configuration has been rejected on the last `load`.
+pub fn invalid() -> Option<String> {
+    INVALID.read().clone()
+}
+
+/// Where the settings have been read from, like `file /etc/libctehxk2.yaml, env K2_LOG_LEVEL`.
+pub fn source() -> String {
+    SOURCE.read().clone()
+}
+
+fn describe_source() -> String {
+    let mut sources = Vec::new();
+
+    let file = CFG_EXTENSIONS
+        .iter()
+        .map(|extension| Path::new(CFG_FILE).with_extension(extension))
+        .find(|path| path.is_file());
+    if let Some(file) = file {
+        let path = file.canonicalize().unwrap_or(file);
+        sources.push(format!("file {}", path.display()));
+    }
+
+    let mut variables = env::vars()
+        .filter(|(key, value)| key.starts_with("K2_") && !value.is_empty())
+        .map(|(key, _)| key)
+        .collect::<Vec<_>>();
+    if !variables.is_empty() {
+        variables.sort();
+        sources.push(format!("env {}", variables.join(" ")));
+    }
+
+    if sources.is_empty() {
+        String::from("defaults")
+    } else {
+        sources.join(", ")
+    }
+}
+
+/// Splits a comma separated list, ignoring empty entries.
+pub fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(String::from)
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::tests::random_string;
+    use crate::tests::{random_string, Pki};
+    use data_encoding::BASE64;
     use spectral::assert_that;
-    use std::{env, fs::File, io::Write, path::MAIN_SEPARATOR};
+    use std::{env, fs, fs::File, io::Write, path::MAIN_SEPARATOR};
     use tempfile::tempdir;
 
+    fn defaults() -> Settings {
+        Settings {
+            timeout: None,
+            connect_timeout: None,
+            read_timeout: None,
+            init_timeout: None,
+            data_timeout: None,
+            close_timeout: None,
+            shutdown_timeout: 1000,
+            backend: backend::Kind::Rest,
+            base_url: vec![String::from("http://localhost:8088/k2/ctapi/")],
+            log_level: String::from("Error"),
+            log_path: None,
+            log_apdu: apdu::Policy::HeaderOnly,
+            preflight: preflight::Policy::Off,
+            preflight_path: None,
+            preflight_version_pointer: None,
+            ctn: None,
+            pn: None,
+            terminals: Vec::new(),
+            status_on_unreachable: Status::ERR_HTSI,
+            status_on_timeout: Status::ERR_HTSI,
+            status_on_http_error: Status::ERR_HTSI,
+            status_on_malformed: Status::ERR_HTSI,
+            status_on_session_lost: Status::ERR_CT,
+            session_lost_status_codes: vec![404, 410],
+            client_cert: None,
+            client_key: None,
+            client_pkcs12: None,
+            client_pkcs12_password: None,
+            ca_file: None,
+            builtin_roots: true,
+            pinned_public_keys: Vec::new(),
+            pool_size: 4,
+            pool_idle_timeout: 30000,
+            retry_attempts: 1,
+            retry_backoff: 100,
+            retry_max_backoff: 2000,
+            failover_cooldown: 30000,
+            proxy: None,
+            proxy_auth: None,
+            no_proxy: Vec::new(),
+            proxy_from_env: false,
+            auth_user: None,
+            auth_password: None,
+            auth_token: None,
+            oauth2_token_url: None,
+            oauth2_client_id: None,
+            oauth2_client_secret: None,
+            oauth2_scope: None,
+            oauth2_timeout: 5000,
+            sim_card_image: None,
+            record_path: None,
+            journal_path: None,
+            replay_path: None,
+        }
+    }
+
     #[test]
     #[serial]
     fn default_configuration() {
         let default = Settings::init();
 
-        assert_eq!(
-            default.ok(),
-            Some(Settings {
-                timeout: None,
-                base_url: String::from("http://localhost:8088/k2/ctapi/"),
-                log_level: String::from("Error"),
-                log_path: None,
-                ctn: None,
-                pn: None,
-            })
-        );
+        assert_eq!(default.ok(), Some(defaults()));
     }
 
     #[test]
@@ -109,11 +516,7 @@ mod tests {
             Settings::init().ok(),
             Some(Settings {
                 timeout: Some(timeout),
-                base_url: String::from("http://localhost:8088/k2/ctapi/"),
-                log_level: String::from("Error"),
-                log_path: None,
-                ctn: None,
-                pn: None,
+                ..defaults()
             })
         );
 
@@ -124,11 +527,8 @@ mod tests {
             Settings::init().ok(),
             Some(Settings {
                 timeout: Some(timeout),
-                base_url: base_url.clone(),
-                log_level: String::from("Error"),
-                log_path: None,
-                ctn: None,
-                pn: None,
+                base_url: vec![base_url.clone()],
+                ..defaults()
             })
         );
 
@@ -139,11 +539,9 @@ mod tests {
             Settings::init().ok(),
             Some(Settings {
                 timeout: Some(timeout),
-                base_url: base_url.clone(),
+                base_url: vec![base_url.clone()],
                 log_level: log_level.clone(),
-                log_path: None,
-                ctn: None,
-                pn: None,
+                ..defaults()
             })
         );
 
@@ -159,11 +557,10 @@ mod tests {
             Settings::init().ok(),
             Some(Settings {
                 timeout: Some(timeout),
-                base_url: base_url.clone(),
+                base_url: vec![base_url.clone()],
                 log_level: log_level.clone(),
                 log_path: Some(log_path.clone()),
-                ctn: None,
-                pn: None,
+                ..defaults()
             })
         );
 
@@ -174,11 +571,10 @@ mod tests {
             Settings::init().ok(),
             Some(Settings {
                 timeout: Some(timeout),
-                base_url: base_url.clone(),
+                base_url: vec![base_url.clone()],
                 log_level: log_level.clone(),
                 log_path: Some(log_path.clone()),
-                ctn: None,
-                pn: None,
+                ..defaults()
             })
         );
 
@@ -189,11 +585,12 @@ mod tests {
             Settings::init().ok(),
             Some(Settings {
                 timeout: Some(timeout),
-                base_url,
+                base_url: vec![base_url],
                 log_level,
                 log_path: Some(log_path),
                 ctn: Some(ctn),
                 pn: Some(pn),
+                ..defaults()
             })
         );
 
@@ -218,7 +615,7 @@ mod tests {
 
         let config_file_path = config_file_folder.path().join(format!("{}.json", CFG_FILE));
         let mut config_file = File::create(config_file_path).unwrap();
-        let _ = env::set_current_dir(config_file_folder.path().to_owned());
+        let _ = env::set_current_dir(config_file_folder.path());
 
         let config = json!({
             "log_level": "debug",
@@ -235,7 +632,7 @@ mod tests {
             Settings::init().ok(),
             Some(Settings {
                 timeout: config["timeout"].as_u64(),
-                base_url: String::from(config["base_url"].as_str().unwrap()),
+                base_url: vec![String::from(config["base_url"].as_str().unwrap())],
                 log_level: String::from(config["log_level"].as_str().unwrap()),
                 log_path: Some(String::from(config["log_path"].as_str().unwrap())),
                 ctn: Some(
@@ -254,6 +651,7 @@ mod tests {
                         .parse::<u16>()
                         .unwrap()
                 ),
+                ..defaults()
             })
         );
     }
@@ -264,7 +662,7 @@ mod tests {
         let config_file_folder = tempdir().unwrap();
         let config_file_path = config_file_folder.path().join(format!("{}.yaml", CFG_FILE));
         let mut config_file = File::create(config_file_path).unwrap();
-        let _ = env::set_current_dir(config_file_folder.path().to_owned());
+        let _ = env::set_current_dir(config_file_folder.path());
 
         let config = "
 timeout: 300
@@ -279,11 +677,8 @@ log_level: debug
             Settings::init().ok(),
             Some(Settings {
                 timeout: Some(300),
-                base_url: String::from("http://localhost:8088/k2/ctapi/"),
                 log_level: String::from("trace"),
-                log_path: None,
-                ctn: None,
-                pn: None,
+                ..defaults()
             })
         );
 
@@ -299,16 +694,65 @@ log_level: debug
         assert_eq!(
             Settings::init().ok(),
             Some(Settings {
-                timeout: None,
-                base_url: format!("{}/", url),
-                log_level: String::from("Error"),
-                log_path: None,
-                ctn: None,
-                pn: None,
+                base_url: vec![format!("{}/", url)],
+                ..defaults()
+            })
+        );
+
+        env::remove_var("K2_BASE_URL");
+    }
+
+    #[test]
+    #[serial]
+    fn multiple_base_urls_from_env() {
+        env::set_var(
+            "K2_BASE_URL",
+            "http://k2-a:8088/k2/ctapi, http://k2-b:8088/k2/ctapi/",
+        );
+        env::set_var("K2_FAILOVER_COOLDOWN", "1000");
+
+        assert_eq!(
+            Settings::init().ok(),
+            Some(Settings {
+                base_url: vec![
+                    String::from("http://k2-a:8088/k2/ctapi/"),
+                    String::from("http://k2-b:8088/k2/ctapi/")
+                ],
+                failover_cooldown: 1000,
+                ..defaults()
             })
         );
 
         env::remove_var("K2_BASE_URL");
+        env::remove_var("K2_FAILOVER_COOLDOWN");
+    }
+
+    #[test]
+    #[serial]
+    fn multiple_base_urls_from_config_file() {
+        let config_file_folder = tempdir().unwrap();
+        let config_file_path = config_file_folder.path().join(format!("{}.yaml", CFG_FILE));
+        let mut config_file = File::create(config_file_path).unwrap();
+        let _ = env::set_current_dir(config_file_folder.path());
+
+        let config = "
+base_url:
+  - http://k2-a:8088/k2/ctapi
+  - http://k2-b:8088/k2/ctapi
+";
+
+        writeln!(config_file, "{}", config).unwrap();
+
+        assert_eq!(
+            Settings::init().ok(),
+            Some(Settings {
+                base_url: vec![
+                    String::from("http://k2-a:8088/k2/ctapi/"),
+                    String::from("http://k2-b:8088/k2/ctapi/")
+                ],
+                ..defaults()
+            })
+        );
     }
 
     #[test]
@@ -321,12 +765,8 @@ log_level: debug
         assert_eq!(
             Settings::init().ok(),
             Some(Settings {
-                timeout: None,
-                base_url: String::from("http://localhost:8088/k2/ctapi/"),
-                log_level: String::from("Error"),
                 log_path: Some(format!("{}{}", path_str, MAIN_SEPARATOR)),
-                ctn: None,
-                pn: None,
+                ..defaults()
             })
         );
 
@@ -339,7 +779,7 @@ log_level: debug
         let config_file_folder = tempdir().unwrap();
         let config_file_path = config_file_folder.path().join(format!("{}.ini", CFG_FILE));
         let mut config_file = File::create(config_file_path).unwrap();
-        let _ = env::set_current_dir(config_file_folder.path().to_owned());
+        let _ = env::set_current_dir(config_file_folder.path());
 
         let config = "
 timeout = 300
@@ -352,11 +792,8 @@ log_level = \"debug\"
             Settings::init().ok(),
             Some(Settings {
                 timeout: Some(300),
-                base_url: String::from("http://localhost:8088/k2/ctapi/"),
                 log_level: String::from("debug"),
-                log_path: None,
-                ctn: None,
-                pn: None,
+                ..defaults()
             })
         );
     }
@@ -383,23 +820,357 @@ log_level = \"debug\"
         env::remove_var("K2_BASE_URL");
     }
 
+    #[test]
+    #[serial]
+    fn status_mapping_from_env() {
+        env::set_var("K2_STATUS_ON_UNREACHABLE", "ERR_TRANS");
+        env::set_var("K2_STATUS_ON_MALFORMED", "ERR_HOST");
+
+        assert_eq!(
+            Settings::init().ok(),
+            Some(Settings {
+                status_on_unreachable: Status::ERR_TRANS,
+                status_on_malformed: Status::ERR_HOST,
+                ..defaults()
+            })
+        );
+
+        env::remove_var("K2_STATUS_ON_UNREACHABLE");
+        env::remove_var("K2_STATUS_ON_MALFORMED");
+    }
+
+    #[test]
+    #[serial]
+    fn error_with_non_failure_status_mapping() {
+        env::set_var("K2_STATUS_ON_TIMEOUT", "OK");
+        assert!(Settings::init().is_err());
+
+        env::set_var("K2_STATUS_ON_TIMEOUT", "ERR_UNKNOWN");
+        assert!(Settings::init().is_err());
+
+        env::remove_var("K2_STATUS_ON_TIMEOUT");
+    }
+
+    #[test]
+    #[serial]
+    fn client_certificate_from_env() {
+        let folder = tempdir().unwrap();
+        let cert = folder.path().join("client.crt");
+        let key = folder.path().join("client.key");
+        File::create(&cert).unwrap();
+        File::create(&key).unwrap();
+
+        env::set_var("K2_CLIENT_CERT", cert.to_str().unwrap());
+        assert!(Settings::init().is_err());
+
+        env::set_var("K2_CLIENT_KEY", key.to_str().unwrap());
+        assert_eq!(
+            Settings::init().ok(),
+            Some(Settings {
+                client_cert: Some(String::from(cert.to_str().unwrap())),
+                client_key: Some(String::from(key.to_str().unwrap())),
+                ..defaults()
+            })
+        );
+
+        env::set_var("K2_CLIENT_PKCS12", cert.to_str().unwrap());
+        assert!(Settings::init().is_err());
+
+        env::remove_var("K2_CLIENT_CERT");
+        env::remove_var("K2_CLIENT_KEY");
+        env::remove_var("K2_CLIENT_PKCS12");
+    }
+
+    #[test]
+    #[serial]
+    fn error_with_missing_client_certificate_file() {
+        env::set_var("K2_CLIENT_PKCS12", random_string(100));
+
+        assert!(Settings::init().is_err());
+
+        env::remove_var("K2_CLIENT_PKCS12");
+    }
+
+    #[test]
+    #[serial]
+    fn trust_settings_from_env() {
+        let folder = tempdir().unwrap();
+        let ca_file = folder.path().join("ca.pem");
+        fs::write(&ca_file, Pki::new().ca_pem()).unwrap();
+        let pin = format!("sha256/{}", BASE64.encode(&[7; 32]));
+
+        env::set_var("K2_CA_FILE", ca_file.to_str().unwrap());
+        env::set_var("K2_BUILTIN_ROOTS", "false");
+        env::set_var("K2_PINNED_PUBLIC_KEYS", format!("{}, {}", pin, pin));
+
+        assert_eq!(
+            Settings::init().ok(),
+            Some(Settings {
+                ca_file: Some(String::from(ca_file.to_str().unwrap())),
+                builtin_roots: false,
+                pinned_public_keys: vec![pin.clone(), pin],
+                ..defaults()
+            })
+        );
+
+        env::remove_var("K2_CA_FILE");
+        env::remove_var("K2_BUILTIN_ROOTS");
+        env::remove_var("K2_PINNED_PUBLIC_KEYS");
+    }
+
+    #[test]
+    #[serial]
+    fn error_with_invalid_trust_settings() {
+        env::set_var("K2_BUILTIN_ROOTS", "false");
+        assert!(Settings::init().is_err());
+        env::remove_var("K2_BUILTIN_ROOTS");
+
+        let folder = tempdir().unwrap();
+        let ca_file = folder.path().join("ca.pem");
+        fs::write(&ca_file, random_string(100)).unwrap();
+        env::set_var("K2_CA_FILE", ca_file.to_str().unwrap());
+        assert!(Settings::init().is_err());
+        env::remove_var("K2_CA_FILE");
+
+        env::set_var(
+            "K2_PINNED_PUBLIC_KEYS",
+            format!("sha256/{}", random_string(10)),
+        );
+        assert!(Settings::init().is_err());
+        env::remove_var("K2_PINNED_PUBLIC_KEYS");
+    }
+
+    #[test]
+    #[serial]
+    fn pool_settings_from_env() {
+        env::set_var("K2_POOL_SIZE", "0");
+        env::set_var("K2_POOL_IDLE_TIMEOUT", "500");
+
+        assert_eq!(
+            Settings::init().ok(),
+            Some(Settings {
+                pool_size: 0,
+                pool_idle_timeout: 500,
+                ..defaults()
+            })
+        );
+
+        env::remove_var("K2_POOL_SIZE");
+        env::remove_var("K2_POOL_IDLE_TIMEOUT");
+    }
+
+    #[test]
+    #[serial]
+    fn retry_settings_from_env() {
+        env::set_var("K2_RETRY_ATTEMPTS", "5");
+        env::set_var("K2_RETRY_BACKOFF", "10");
+        env::set_var("K2_RETRY_MAX_BACKOFF", "50");
+
+        assert_eq!(
+            Settings::init().ok(),
+            Some(Settings {
+                retry_attempts: 5,
+                retry_backoff: 10,
+                retry_max_backoff: 50,
+                ..defaults()
+            })
+        );
+
+        env::set_var("K2_RETRY_ATTEMPTS", "0");
+        assert!(Settings::init().is_err());
+
+        env::remove_var("K2_RETRY_ATTEMPTS");
+        env::remove_var("K2_RETRY_BACKOFF");
+        env::remove_var("K2_RETRY_MAX_BACKOFF");
+    }
+
+    #[test]
+    #[serial]
+    fn unknown_backend() {
+        env::set_var("K2_BACKEND", "REST");
+        assert!(Settings::init().is_err());
+
+        env::set_var("K2_BACKEND", "rest");
+        assert_eq!(Settings::init().ok(), Some(defaults()));
+
+        env::remove_var("K2_BACKEND");
+    }
+
+    #[test]
+    #[serial]
+    fn timeout_settings_from_env() {
+        env::set_var("K2_CONNECT_TIMEOUT", "500");
+        env::set_var("K2_READ_TIMEOUT", "0");
+        env::set_var("K2_INIT_TIMEOUT", "2000");
+        env::set_var("K2_DATA_TIMEOUT", "60000");
+        env::set_var("K2_CLOSE_TIMEOUT", "1500");
+
+        assert_eq!(
+            Settings::init().ok(),
+            Some(Settings {
+                connect_timeout: Some(500),
+                read_timeout: Some(0),
+                init_timeout: Some(2000),
+                data_timeout: Some(60000),
+                close_timeout: Some(1500),
+                ..defaults()
+            })
+        );
+
+        env::remove_var("K2_CONNECT_TIMEOUT");
+        env::remove_var("K2_READ_TIMEOUT");
+        env::remove_var("K2_INIT_TIMEOUT");
+        env::remove_var("K2_DATA_TIMEOUT");
+        env::remove_var("K2_CLOSE_TIMEOUT");
+    }
+
+    #[test]
+    #[serial]
+    fn source_of_settings() {
+        let config_file_folder = tempdir().unwrap();
+        let _ = env::set_current_dir(config_file_folder.path());
+
+        let _ = Settings::init().unwrap();
+        assert_eq!("defaults", source());
+
+        let config_file_path = config_file_folder.path().join(format!("{}.yml", CFG_FILE));
+        writeln!(File::create(&config_file_path).unwrap(), "timeout: 300").unwrap();
+        env::set_var("K2_LOG_LEVEL", "debug");
+        env::set_var("K2_PN", "");
+
+        let _ = Settings::init().unwrap();
+        assert_eq!(
+            format!(
+                "file {}, env K2_LOG_LEVEL",
+                config_file_path.canonicalize().unwrap().display()
+            ),
+            source()
+        );
+
+        env::remove_var("K2_LOG_LEVEL");
+        env::remove_var("K2_PN");
+    }
+
+    #[test]
+    #[serial]
+    fn terminal_mappings() {
+        env::set_var("K2_TERMINALS", "1:3, 2:7");
+
+        assert_eq!(
+            Settings::init().ok(),
+            Some(Settings {
+                terminals: vec![
+                    TerminalMapping { ctn: 1, pn: 3 },
+                    TerminalMapping { ctn: 2, pn: 7 }
+                ],
+                ..defaults()
+            })
+        );
+
+        env::set_var("K2_TERMINALS", "1:3,1:7");
+        assert!(Settings::init().is_err());
+
+        env::set_var("K2_TERMINALS", "1");
+        assert!(Settings::init().is_err());
+
+        env::remove_var("K2_TERMINALS");
+
+        let config_file_folder = tempdir().unwrap();
+        let config_file_path = config_file_folder.path().join(format!("{}.yaml", CFG_FILE));
+        let mut config_file = File::create(config_file_path).unwrap();
+        let _ = env::set_current_dir(config_file_folder.path());
+
+        writeln!(
+            config_file,
+            "terminals: [{{ctn: 1, pn: 3}}, {{ctn: 2, pn: 7}}]"
+        )
+        .unwrap();
+
+        assert_eq!(
+            Settings::init().ok(),
+            Some(Settings {
+                terminals: vec![
+                    TerminalMapping { ctn: 1, pn: 3 },
+                    TerminalMapping { ctn: 2, pn: 7 }
+                ],
+                ..defaults()
+            })
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn proxy_settings_from_env() {
+        env::set_var("K2_PROXY", "http://proxy.local:3128");
+        env::set_var("K2_PROXY_AUTH", "user:secret");
+        env::set_var("K2_NO_PROXY", "localhost, .intranet");
+        env::set_var("K2_PROXY_FROM_ENV", "true");
+
+        assert_eq!(
+            Settings::init().ok(),
+            Some(Settings {
+                proxy: Some(String::from("http://proxy.local:3128")),
+                proxy_auth: Some(String::from("user:secret")),
+                no_proxy: vec![String::from("localhost"), String::from(".intranet")],
+                proxy_from_env: true,
+                ..defaults()
+            })
+        );
+
+        env::set_var("K2_PROXY_AUTH", "user");
+        assert!(Settings::init().is_err());
+
+        env::remove_var("K2_PROXY");
+        env::remove_var("K2_PROXY_AUTH");
+        env::remove_var("K2_NO_PROXY");
+        env::remove_var("K2_PROXY_FROM_ENV");
+    }
+
+    #[test]
+    #[serial]
+    fn auth_settings_from_env() {
+        env::set_var("K2_OAUTH2_TOKEN_URL", "http://localhost:8080/token");
+        env::set_var("K2_OAUTH2_CLIENT_ID", "basecamp");
+
+        assert!(Settings::init().is_err());
+
+        env::set_var("K2_OAUTH2_CLIENT_SECRET", "secret");
+        env::set_var("K2_OAUTH2_SCOPE", "ctapi");
+
+        assert_eq!(
+            Settings::init().ok(),
+            Some(Settings {
+                oauth2_token_url: Some(String::from("http://localhost:8080/token")),
+                oauth2_client_id: Some(String::from("basecamp")),
+                oauth2_client_secret: Some(String::from("secret")),
+                oauth2_scope: Some(String::from("ctapi")),
+                ..defaults()
+            })
+        );
+
+        env::set_var("K2_AUTH_TOKEN", "token");
+        assert!(Settings::init().is_err());
+
+        env::remove_var("K2_OAUTH2_TOKEN_URL");
+        env::remove_var("K2_OAUTH2_CLIENT_ID");
+        env::remove_var("K2_OAUTH2_CLIENT_SECRET");
+        env::remove_var("K2_OAUTH2_SCOPE");
+        env::remove_var("K2_AUTH_TOKEN");
+    }
+
     #[test]
     #[serial]
     fn enforce_ctn_and_pn_were_set() {
         let mut settings = Settings::init().unwrap();
-        assert_that(&settings)
-            .map(|val| &val.ctn)
-            .is_equal_to(&None);
-        assert_that(&settings).map(|val| &val.pn).is_equal_to(&None);
+        assert_that(&settings).map(|val| &val.ctn).is_equal_to(None);
+        assert_that(&settings).map(|val| &val.pn).is_equal_to(None);
 
         let ctn = rand::random::<u16>();
         env::set_var("K2_CTN", format!("{}", ctn));
 
         settings = Settings::init().unwrap();
-        assert_that(&settings)
-            .map(|val| &val.ctn)
-            .is_equal_to(&None);
-        assert_that(&settings).map(|val| &val.pn).is_equal_to(&None);
+        assert_that(&settings).map(|val| &val.ctn).is_equal_to(None);
+        assert_that(&settings).map(|val| &val.pn).is_equal_to(None);
 
         env::remove_var("K2_CTN");
 
@@ -407,20 +1178,18 @@ log_level = \"debug\"
         env::set_var("K2_PN", format!("{}", pn));
 
         settings = Settings::init().unwrap();
-        assert_that(&settings)
-            .map(|val| &val.ctn)
-            .is_equal_to(&None);
-        assert_that(&settings).map(|val| &val.pn).is_equal_to(&None);
+        assert_that(&settings).map(|val| &val.ctn).is_equal_to(None);
+        assert_that(&settings).map(|val| &val.pn).is_equal_to(None);
 
         env::set_var("K2_CTN", format!("{}", ctn));
 
         settings = Settings::init().unwrap();
         assert_that(&settings)
             .map(|val| &val.ctn)
-            .is_equal_to(&Some(ctn));
+            .is_equal_to(Some(ctn));
         assert_that(&settings)
             .map(|val| &val.pn)
-            .is_equal_to(&Some(pn));
+            .is_equal_to(Some(pn));
 
         env::remove_var("K2_CTN");
         env::remove_var("K2_PN");
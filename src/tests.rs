@@ -1,19 +1,242 @@
 use super::*;
-use crate::{ctapi::MAP, Settings, CONFIG};
+use crate::{
+    ctapi::{Session, Terminal, MAP},
+    CONFIG,
+};
+use rustls::{
+    pki_types::{CertificateDer, PrivatePkcs8KeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig, ServerConnection, StreamOwned,
+};
 use std::{
     collections::HashMap,
     env::{remove_var, set_var},
+    io::{Read, Write},
+    net::TcpListener,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+};
+use tempfile::NamedTempFile;
+use wiremock::{
+    matchers::{any, body_string},
+    Mock, MockServer, ResponseTemplate,
 };
-use wiremock::{matchers::body_string, Mock, MockServer, ResponseTemplate};
 
 pub fn init_config_clear_map() {
     let mut config_guard = CONFIG.write();
-    *config_guard = Settings::init().unwrap();
+    *config_guard = crate::settings::load();
+    if let Some(why) = crate::settings::invalid() {
+        panic!("{}", why);
+    }
     drop(config_guard);
 
     let mut map_guard = MAP.write();
     *map_guard = HashMap::new();
     drop(map_guard);
+
+    crate::http::reset();
+    crate::last_error::reset();
+    crate::preflight::reset();
+}
+
+pub struct Pki {
+    ca: rcgen::Certificate,
+    ca_key: rcgen::KeyPair,
+}
+
+pub struct Identity {
+    pub cert: rcgen::Certificate,
+    pub key: rcgen::KeyPair,
+}
+
+impl Pki {
+    pub fn new() -> Self {
+        let ca_key = rcgen::KeyPair::generate().unwrap();
+        let mut params = rcgen::CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = params.self_signed(&ca_key).unwrap();
+
+        Pki { ca, ca_key }
+    }
+
+    pub fn ca_der(&self) -> CertificateDer<'static> {
+        self.ca.der().clone()
+    }
+
+    pub fn ca_pem(&self) -> String {
+        self.ca.pem()
+    }
+
+    pub fn ca_file(&self) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(self.ca_pem().as_bytes()).unwrap();
+        file
+    }
+
+    pub fn issue(&self, name: &str) -> Identity {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = rcgen::CertificateParams::new(vec![name.to_string()])
+            .unwrap()
+            .signed_by(&key, &self.ca, &self.ca_key)
+            .unwrap();
+
+        Identity { cert, key }
+    }
+}
+
+/// Serves `body` over TLS on a random local port and returns the port.
+pub fn tls_server(pki: &Pki, server: &Identity, client_auth: bool, body: &'static str) -> u16 {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .unwrap();
+    let builder = if client_auth {
+        let mut roots = RootCertStore::empty();
+        roots.add(pki.ca_der()).unwrap();
+        let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+            .build()
+            .unwrap();
+        builder.with_client_cert_verifier(verifier)
+    } else {
+        builder.with_no_client_auth()
+    };
+    let config = Arc::new(
+        builder
+            .with_single_cert(
+                vec![server.cert.der().clone()],
+                PrivatePkcs8KeyDer::from(server.key.serialize_der()).into(),
+            )
+            .unwrap(),
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let conn = ServerConnection::new(config.clone()).unwrap();
+            let mut tls = StreamOwned::new(conn, stream);
+            if read_http_request(&mut tls).is_some() {
+                let _ = write!(
+                    tls,
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                tls.conn.send_close_notify();
+                let _ = tls.flush();
+            }
+        }
+    });
+
+    port
+}
+
+/// URL of a local port nobody listens on, so requests to it are never sent.
+pub fn unreachable_url() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port())
+}
+
+/// Serves `body` over plain http with keep-alive on a random local port.
+/// Returns the port and the number of accepted connections.
+pub fn http_server(body: &'static str) -> (u16, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = connections.clone();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let _ = counter.fetch_add(1, Ordering::SeqCst);
+            let _ = thread::spawn(move || {
+                while read_http_request(&mut stream).is_some() {
+                    let _ = write!(
+                        stream,
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                }
+            });
+        }
+    });
+
+    (port, connections)
+}
+
+/// Stands in for a forwarding proxy: records each request and answers it itself.
+pub fn proxy_server(body: &'static str) -> (u16, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let recorded = requests.clone();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let recorded = recorded.clone();
+            let _ = thread::spawn(move || {
+                while let Some(request) = read_http_request(&mut stream) {
+                    recorded.lock().push(request);
+                    let _ = write!(
+                        stream,
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                }
+            });
+        }
+    });
+
+    (port, requests)
+}
+
+/// Reads a single HTTP request including its body.
+pub fn read_http_request(stream: &mut impl Read) -> Option<String> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 1024];
+    loop {
+        match stream.read(&mut chunk) {
+            Ok(0) | Err(_) => return None,
+            Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+        }
+
+        let request = String::from_utf8_lossy(&buffer).to_string();
+        if let Some(end_of_head) = request.find("\r\n\r\n") {
+            let content_length = request[..end_of_head]
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+                .unwrap_or(0);
+
+            if buffer.len() >= end_of_head + 4 + content_length {
+                return Some(request);
+            }
+        }
+    }
+}
+
+/// Registers an opened terminal on the first configured K2.
+pub fn insert_terminal(ctn: u16, pn: u16) {
+    let base_url = CONFIG.read().base_url[0].clone();
+    insert_session(ctn, Terminal { pn, base_url });
+}
+
+pub fn insert_session(ctn: u16, terminal: Terminal) {
+    let session = Session {
+        pn: terminal.pn,
+        terminal: Mutex::new(Some(terminal)),
+    };
+    let _ = MAP.write().insert(ctn, Arc::new(session));
+}
+
+/// Terminal opened for the ctn.
+pub fn terminal(ctn: u16) -> Option<Terminal> {
+    MAP.read()
+        .get(&ctn)
+        .and_then(|session| session.terminal.lock().clone())
 }
 
 pub fn random_string(size: usize) -> String {
@@ -44,6 +267,27 @@ async fn init() {
     remove_var("K2_BASE_URL");
 }
 
+#[async_std::test]
+#[serial]
+async fn init_with_malformed_response() {
+    let mock_server = MockServer::start().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200).set_body_string("hello world"))
+        .mount(&mock_server)
+        .await;
+
+    set_var("K2_BASE_URL", mock_server.uri());
+    set_var("K2_STATUS_ON_MALFORMED", "ERR_HOST");
+    init_config_clear_map();
+
+    let ctn = rand::random::<u16>();
+    let pn = rand::random::<u16>();
+
+    assert_eq!(-127, CT_init(ctn, pn));
+    remove_var("K2_BASE_URL");
+    remove_var("K2_STATUS_ON_MALFORMED");
+}
+
 #[async_std::test]
 #[serial]
 async fn close_with_error() {
@@ -59,7 +303,7 @@ async fn close_with_error() {
     let ctn = rand::random::<u16>();
     let pn = rand::random::<u16>();
 
-    let _ = MAP.write().insert(ctn, pn);
+    insert_terminal(ctn, pn);
 
     assert_eq!(-128, CT_close(ctn));
     remove_var("K2_BASE_URL");
@@ -87,11 +331,11 @@ async fn data_with_error() {
     let commands_ptr: *const u8 = &commands[0];
     let lenc: u16 = rand::random::<u16>();
 
-    let mut response: [u8; std::u16::MAX as usize] = [rand::random::<u8>(); std::u16::MAX as usize];
+    let mut response: [u8; u16::MAX as usize] = [rand::random::<u8>(); u16::MAX as usize];
     let response_ptr: *mut u8 = &mut response[0];
     let mut lenr: u16 = rand::random::<u16>();
 
-    let _ = MAP.write().insert(ctn, pn);
+    insert_terminal(ctn, pn);
 
     assert_eq!(
         -128,
@@ -122,7 +366,7 @@ fn data_null_pointer() -> anyhow::Result<()> {
     let commands_ptr: *const u8 = &commands[0];
     let lenc: u16 = rand::random::<u16>();
 
-    let mut response: [u8; std::u16::MAX as usize] = [rand::random::<u8>(); std::u16::MAX as usize];
+    let mut response: [u8; u16::MAX as usize] = [rand::random::<u8>(); u16::MAX as usize];
     let response_ptr: *mut u8 = &mut response[0];
     let mut lenr: u16 = rand::random::<u16>();
 
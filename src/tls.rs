@@ -0,0 +1,189 @@
+use crate::settings::Settings;
+use data_encoding::BASE64;
+use p12_keystore::KeyStore;
+use ring::digest::{digest, SHA256};
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    crypto::ring as provider,
+    pki_types::{
+        pem::PemObject, CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime,
+    },
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use std::{convert::TryFrom, fs, sync::Arc};
+use webpki::EndEntityCert;
+
+pub fn client_config(config: &Settings) -> anyhow::Result<Arc<ClientConfig>> {
+    let crypto = Arc::new(provider::default_provider());
+    let roots = Arc::new(root_store(config)?);
+    let builder = ClientConfig::builder_with_provider(crypto.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let builder = if config.pinned_public_keys.is_empty() {
+        builder.with_root_certificates(roots)
+    } else {
+        let pins = config
+            .pinned_public_keys
+            .iter()
+            .map(|pin| decode_pin(pin))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let verifier = PinningVerifier {
+            inner: WebPkiServerVerifier::builder_with_provider(roots, crypto).build()?,
+            pins,
+        };
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+    };
+
+    let tls_config = match client_identity(config)? {
+        Some((chain, key)) => {
+            debug!("Using client certificate for TLS connections.");
+            builder.with_client_auth_cert(chain, key)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(Arc::new(tls_config))
+}
+
+/// Reads all certificates of a PEM bundle.
+pub fn read_ca_file(path: &str) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|why| format_err!("Failed to read ca_file: {}", why))?;
+
+    if certs.is_empty() {
+        bail!("No certificate found in ca_file");
+    }
+
+    Ok(certs)
+}
+
+/// Decodes a pin in the form `sha256/<base64>` or `<base64>` into the SHA-256 digest.
+pub fn decode_pin(pin: &str) -> anyhow::Result<Vec<u8>> {
+    let encoded = pin.trim();
+    let encoded = encoded.strip_prefix("sha256/").unwrap_or(encoded);
+    match BASE64.decode(encoded.as_bytes()) {
+        Ok(hash) if hash.len() == SHA256.output_len() => Ok(hash),
+        _ => bail!("Invalid SHA-256 public key pin: {}", pin),
+    }
+}
+
+fn root_store(config: &Settings) -> anyhow::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    if config.builtin_roots {
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    }
+
+    if let Some(ca_file) = &config.ca_file {
+        let (added, ignored) = roots.add_parsable_certificates(read_ca_file(ca_file)?);
+        debug!(
+            "Added {} certificates from ca_file, ignored {}.",
+            added, ignored
+        );
+    }
+
+    Ok(roots)
+}
+
+#[derive(Debug)]
+struct PinningVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<Vec<u8>>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+
+        let cert = EndEntityCert::try_from(end_entity)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        let hash = digest(&SHA256, cert.subject_public_key_info().as_ref());
+        if self.pins.iter().any(|pin| pin.as_slice() == hash.as_ref()) {
+            Ok(verified)
+        } else {
+            error!(
+                "Public key of K2 certificate (sha256/{}) matches no pin!",
+                BASE64.encode(hash.as_ref())
+            );
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+type Identity = (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>);
+
+fn client_identity(config: &Settings) -> anyhow::Result<Option<Identity>> {
+    if let (Some(cert), Some(key)) = (&config.client_cert, &config.client_key) {
+        let chain = CertificateDer::pem_file_iter(cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|why| format_err!("Failed to read client_cert: {}", why))?;
+        let key = PrivateKeyDer::from_pem_file(key)
+            .map_err(|why| format_err!("Failed to read client_key: {}", why))?;
+
+        return Ok(Some((chain, key)));
+    }
+
+    if let Some(pkcs12) = &config.client_pkcs12 {
+        let password = config.client_pkcs12_password.as_deref().unwrap_or("");
+        let keystore = KeyStore::from_pkcs12(&fs::read(pkcs12)?, password)
+            .map_err(|why| format_err!("Failed to read client_pkcs12: {}", why))?;
+
+        return match keystore.private_key_chain() {
+            Some((_, key_chain)) => {
+                let chain = key_chain
+                    .chain()
+                    .iter()
+                    .map(|cert| CertificateDer::from(cert.as_der().to_vec()))
+                    .collect();
+                let key = PrivatePkcs8KeyDer::from(key_chain.key().to_vec()).into();
+
+                Ok(Some((chain, key)))
+            }
+            None => bail!("No private key found in client_pkcs12"),
+        };
+    }
+
+    Ok(None)
+}
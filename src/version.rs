@@ -0,0 +1,22 @@
+use crate::{settings, CONFIG};
+
+/// Crate version, NUL terminated for `K2_version`.
+pub const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "\0");
+
+pub fn build_info() -> String {
+    // the source is known once the configuration has been read
+    let _ = CONFIG.read();
+
+    let features = match env!("BASECAMP_FEATURES") {
+        "" => "none",
+        features => features,
+    };
+
+    format!(
+        "version={}\nrevision={}\nfeatures={}\nconfig={}",
+        env!("CARGO_PKG_VERSION"),
+        env!("BASECAMP_GIT_REVISION"),
+        features,
+        settings::source()
+    )
+}
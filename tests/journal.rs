@@ -0,0 +1,87 @@
+use dlopen::raw::Library;
+use std::{
+    env, fs,
+    path::PathBuf,
+    process::Command,
+    thread,
+    time::{Duration, Instant},
+};
+use tempfile::tempdir;
+
+#[cfg(target_os = "windows")]
+const LIB_PATH: &str = "./target/debug/ctehxk2.dll";
+#[cfg(target_os = "linux")]
+const LIB_PATH: &str = "./target/debug/libctehxk2.so";
+#[cfg(target_os = "macos")]
+const LIB_PATH: &str = "./target/debug/libctehxk2.dylib";
+
+const TERMINALS: u16 = 50;
+const WRITERS: [u16; 2] = [1, 1001];
+
+/// Opens card terminals in a process of its own, if started by `processes_share_journal`.
+#[test]
+fn journal_writer() -> anyhow::Result<()> {
+    let first = match env::var("JOURNAL_WRITER") {
+        Ok(first) => first.parse::<u16>()?,
+        Err(_) => return Ok(()),
+    };
+
+    let lib = Library::open(LIB_PATH)?;
+    let init: unsafe extern "system" fn(u16, u16) -> i8 = unsafe { lib.symbol("CT_init") }?;
+    for ctn in first..first + TERMINALS {
+        assert_eq!(0, unsafe { init(ctn, 1) });
+    }
+
+    // stay alive until all are done, otherwise the others close our terminals as orphaned
+    let folder = PathBuf::from(env::var("JOURNAL_WRITERS_DONE")?);
+    fs::write(folder.join(first.to_string()), "")?;
+    let started = Instant::now();
+    while fs::read_dir(&folder)?.count() < WRITERS.len() {
+        assert!(started.elapsed() < Duration::from_secs(30));
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn processes_share_journal() -> anyhow::Result<()> {
+    let folder = tempdir()?;
+    let path = folder.path().join("journal.json");
+    let done = folder.path().join("done");
+    fs::create_dir(&done)?;
+
+    let writers = WRITERS
+        .iter()
+        .map(|first| {
+            Command::new(env::current_exe()?)
+                .args(["--exact", "journal_writer", "--test-threads=1"])
+                .env("JOURNAL_WRITER", first.to_string())
+                .env("K2_BACKEND", "sim")
+                .env("K2_JOURNAL_PATH", &path)
+                // leave the terminals open on exit, as a crashed process does
+                .env("K2_SHUTDOWN_TIMEOUT", "0")
+                .env("JOURNAL_WRITERS_DONE", &done)
+                .spawn()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    for mut writer in writers {
+        assert!(writer.wait()?.success());
+    }
+
+    let entries: Vec<serde_json::Value> = serde_json::from_str(&fs::read_to_string(&path)?)?;
+    let mut ctns = entries
+        .iter()
+        .filter_map(|entry| entry["ctn"].as_u64())
+        .collect::<Vec<_>>();
+    ctns.sort_unstable();
+
+    let expected = WRITERS
+        .iter()
+        .flat_map(|first| *first..*first + TERMINALS)
+        .map(u64::from)
+        .collect::<Vec<_>>();
+    assert_eq!(expected, ctns);
+
+    Ok(())
+}
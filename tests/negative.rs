@@ -1,6 +1,8 @@
 #[macro_use]
 extern crate serial_test;
 
+use std::str;
+
 #[cfg(target_os = "windows")]
 const LIB_PATH: &str = "./target/debug/ctehxk2.dll";
 #[cfg(target_os = "linux")]
@@ -61,7 +63,7 @@ fn data_null_pointer() -> anyhow::Result<()> {
     let commands_ptr: *const u8 = &commands[0];
     let lenc: u16 = rand::random::<u16>();
 
-    let mut response: [u8; std::u16::MAX as usize] = [rand::random::<u8>(); std::u16::MAX as usize];
+    let mut response: [u8; u16::MAX as usize] = [rand::random::<u8>(); u16::MAX as usize];
     let response_ptr: *mut u8 = &mut response[0];
     let mut lenr: u16 = rand::random::<u16>();
 
@@ -117,5 +119,72 @@ fn data_null_pointer() -> anyhow::Result<()> {
         )
     });
 
+    let commands_null: *const u8 = std::ptr::null();
+    assert_eq!(-128, unsafe {
+        data(
+            ctn,
+            &mut dad,
+            &mut sad,
+            lenc,
+            commands_null,
+            &mut lenr,
+            response_ptr,
+        )
+    });
+
+    assert_eq!(-1, unsafe {
+        data(
+            ctn,
+            &mut dad,
+            &mut sad,
+            0,
+            commands_ptr,
+            &mut lenr,
+            response_ptr,
+        )
+    });
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn last_error() -> anyhow::Result<()> {
+    let lib = dlopen::raw::Library::open(LIB_PATH)?;
+
+    let init: unsafe extern "system" fn(u16, u16) -> i8 = unsafe { lib.symbol("CT_init") }?;
+    let close: unsafe extern "system" fn(u16) -> i8 = unsafe { lib.symbol("CT_close") }?;
+    let last_error: unsafe extern "system" fn(u16, *mut u8, u16) -> u16 =
+        unsafe { lib.symbol("K2_last_error") }?;
+    let last_error_code: unsafe extern "system" fn() -> i32 =
+        unsafe { lib.symbol("K2_last_error_code") }?;
+
+    let ctn = rand::random::<u16>();
+    let mut message = [0xFF; 256];
+
+    assert_eq!(-1, unsafe { close(ctn) });
+    assert_eq!(6, unsafe { last_error_code() });
+
+    let length = unsafe { last_error(ctn, message.as_mut_ptr(), message.len() as u16) };
+    let expected = format!("CT_close(ctn {}): Card terminal has not been opened.", ctn);
+    assert_eq!(expected.len(), length as usize);
+    assert_eq!(expected.as_bytes(), &message[..expected.len()]);
+    assert_eq!(0, message[expected.len()]);
+
+    // truncated to the buffer, but reporting the whole length
+    assert_eq!(length, unsafe { last_error(ctn, message.as_mut_ptr(), 9) });
+    assert_eq!(b"CT_close\0", &message[..9]);
+    assert_eq!(length, unsafe { last_error(ctn, std::ptr::null_mut(), 0) });
+
+    assert_eq!(-128, unsafe { init(ctn, 1) });
+    assert_eq!(1, unsafe { last_error_code() });
+    let length = unsafe { last_error(ctn, message.as_mut_ptr(), message.len() as u16) };
+    assert!(str::from_utf8(&message[..length as usize])?
+        .starts_with(&format!("CT_init(ctn {}): K2 is unreachable", ctn)));
+
+    assert_eq!(0, unsafe {
+        last_error(rand::random::<u16>(), message.as_mut_ptr(), 1)
+    });
+
     Ok(())
 }
@@ -3,7 +3,7 @@ extern crate serial_test;
 
 use dlopen::raw::Library;
 use serde_json::json;
-use std::{env, str, u16::MAX};
+use std::{env, ffi::CStr, os::raw::c_char, str};
 use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
 
 #[cfg(target_os = "windows")]
@@ -59,7 +59,7 @@ async fn init_data_close() -> anyhow::Result<()> {
     let commands_ptr: *const u8 = &commands[0];
     let lenc: u16 = commands.len() as u16;
 
-    let mut response: [u8; MAX as usize] = [rand::random::<u8>(); MAX as usize];
+    let mut response: [u8; u16::MAX as usize] = [rand::random::<u8>(); u16::MAX as usize];
     let response_ptr: *mut u8 = &mut response[0];
     let mut lenr: u16 = response.len() as u16;
 
@@ -80,3 +80,31 @@ async fn init_data_close() -> anyhow::Result<()> {
     env::remove_var("K2_BASE_URL");
     Ok(())
 }
+
+#[test]
+#[serial]
+fn version_and_build_info() -> anyhow::Result<()> {
+    let lib = Library::open(LIB_PATH)?;
+
+    let version: unsafe extern "system" fn() -> *const c_char =
+        unsafe { lib.symbol("K2_version") }?;
+    let build_info: unsafe extern "system" fn(*mut u8, u16) -> u16 =
+        unsafe { lib.symbol("K2_build_info") }?;
+
+    assert_eq!(env!("CARGO_PKG_VERSION"), unsafe {
+        CStr::from_ptr(version()).to_str()?
+    });
+
+    let mut buffer = [0; 1024];
+    let length = unsafe { build_info(buffer.as_mut_ptr(), buffer.len() as u16) } as usize;
+    let info = str::from_utf8(&buffer[..length])?;
+
+    let lines = info.lines().collect::<Vec<_>>();
+    assert_eq!(format!("version={}", env!("CARGO_PKG_VERSION")), lines[0]);
+    assert!(lines[1].starts_with("revision="));
+    assert_eq!("features=none", lines[2]);
+    assert!(lines[3].starts_with("config="));
+    assert_eq!(0, buffer[length]);
+
+    Ok(())
+}
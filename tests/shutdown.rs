@@ -0,0 +1,39 @@
+use dlopen::raw::Library;
+use std::env;
+use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+#[cfg(target_os = "windows")]
+const LIB_PATH: &str = "./target/debug/ctehxk2.dll";
+#[cfg(target_os = "linux")]
+const LIB_PATH: &str = "./target/debug/libctehxk2.so";
+#[cfg(target_os = "macos")]
+const LIB_PATH: &str = "./target/debug/libctehxk2.dylib";
+
+#[async_std::test]
+async fn shutdown_closes_open_terminals() -> anyhow::Result<()> {
+    let ctn = rand::random::<u16>();
+    let pn = rand::random::<u16>();
+
+    let mock_server = MockServer::start().await;
+    Mock::given(matchers::path(format!("/ct_init/{}/{}", ctn, pn)))
+        .respond_with(ResponseTemplate::new(200).set_body_json(0))
+        .mount(&mock_server)
+        .await;
+    Mock::given(matchers::path(format!("/ct_close/{}/{}", ctn, pn)))
+        .respond_with(ResponseTemplate::new(200).set_body_json(0))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    env::set_var("K2_BASE_URL", mock_server.uri());
+
+    let lib = Library::open(LIB_PATH)?;
+    let init: unsafe extern "system" fn(u16, u16) -> i8 = unsafe { lib.symbol("CT_init") }?;
+    let close: unsafe extern "system" fn(u16) -> i8 = unsafe { lib.symbol("CT_close") }?;
+    let shutdown: unsafe extern "system" fn() -> u16 = unsafe { lib.symbol("K2_shutdown") }?;
+
+    assert_eq!(0, unsafe { init(ctn, pn) });
+    assert_eq!(0, unsafe { shutdown() });
+    assert_eq!(-1, unsafe { close(ctn) });
+
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use dlopen::raw::Library;
+use std::{env, thread};
+use wiremock::{matchers, Mock, MockServer, ResponseTemplate};
+
+#[cfg(target_os = "windows")]
+const LIB_PATH: &str = "./target/debug/ctehxk2.dll";
+#[cfg(target_os = "linux")]
+const LIB_PATH: &str = "./target/debug/libctehxk2.so";
+#[cfg(target_os = "macos")]
+const LIB_PATH: &str = "./target/debug/libctehxk2.dylib";
+
+#[async_std::test]
+async fn close_open_terminals_on_unload() -> anyhow::Result<()> {
+    let ctn = rand::random::<u16>();
+    let pn = rand::random::<u16>();
+
+    let mock_server = MockServer::start().await;
+    Mock::given(matchers::path(format!("/ct_init/{}/{}", ctn, pn)))
+        .respond_with(ResponseTemplate::new(200).set_body_json(0))
+        .mount(&mock_server)
+        .await;
+    Mock::given(matchers::path(format!("/ct_close/{}/{}", ctn, pn)))
+        .respond_with(ResponseTemplate::new(200).set_body_json(0))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    env::set_var("K2_BASE_URL", mock_server.uri());
+
+    let lib = Library::open(LIB_PATH)?;
+    let init: unsafe extern "system" fn(u16, u16) -> i8 = unsafe { lib.symbol("CT_init") }?;
+
+    // thread locals of the library keep it loaded until their thread has finished
+    let status = thread::spawn(move || unsafe { init(ctn, pn) })
+        .join()
+        .unwrap();
+    assert_eq!(0, status);
+
+    // the host unloads the library without CT_close
+    drop(lib);
+
+    let requests = mock_server.received_requests().await.unwrap_or_default();
+    assert_eq!(
+        Some(format!("/ct_close/{}/{}", ctn, pn).as_str()),
+        requests.last().map(|request| request.url.path())
+    );
+
+    Ok(())
+}